@@ -22,6 +22,10 @@ struct VoxelMaterial {
 
 @group(2) @binding(100)
 var<uniform> voxel_material: VoxelMaterial;
+@group(2) @binding(101)
+var voxel_textures: texture_2d_array<f32>;
+@group(2) @binding(102)
+var voxel_sampler: sampler;
 
 @fragment
 fn fragment(
@@ -31,9 +35,24 @@ fn fragment(
     // generate a PbrInput struct from the StandardMaterial bindings
     var pbr_input = pbr_input_from_standard_material(in, is_front);
 
+#ifdef VERTEX_UVS
+#ifdef VERTEX_UVS_B
+    // the mesher stores the block texture layer of each face in the second uv channel
+    let layer = i32(round(in.uv_b.y));
+    pbr_input.material.base_color *= textureSample(voxel_textures, voxel_sampler, in.uv, layer);
+#endif
+#endif
+
     // alpha discard
     pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
 
+    // the mesher bakes per-vertex ambient occlusion into the second uv channel, 1 is unoccluded
+    var ao = 1.0;
+#ifdef VERTEX_UVS_B
+    ao = in.uv_b.x;
+#endif
+    pbr_input.diffuse_occlusion *= ao;
+
 #ifdef PREPASS_PIPELINE
     // write the gbuffer, lighting pass id, and optionally normal and motion_vector textures
     let out = deferred_output(in, pbr_input);
@@ -46,6 +65,8 @@ fn fragment(
     } else {
         out.color = pbr_input.material.base_color;
     }
+    // darken direct lighting as well, ambient light alone is too faint for the occlusion to read
+    out.color = vec4<f32>(out.color.rgb * ao, out.color.a);
 
 //    // we can optionally modify the lit color before post-processing is applied
 //    out.color = vec4<f32>(vec4<u32>(out.color * f32(voxel_material.quantize_steps))) / f32(voxel_material.quantize_steps);
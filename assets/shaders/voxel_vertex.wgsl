@@ -0,0 +1,77 @@
+#import bevy_pbr::mesh_functions
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::VertexOutput
+#else
+#import bevy_pbr::forward_io::VertexOutput
+#endif
+
+struct PackedVertex {
+    @builtin(instance_index) instance_index: u32,
+    // position, face, ao and texture layer, then the linear rgba8 color, see ATTRIBUTE_PACKED_VOXEL
+    @location(0) packed: vec2<u32>,
+};
+
+// texture coordinates for a point on a face, matching texture_uv in voxel_mesher.rs. the sampler repeats, so using the
+// position within the chunk gives the same result as measuring from the corner of the quad
+fn voxel_uv(position: vec3<f32>, face: u32) -> vec2<f32> {
+    if face <= 1u {
+        return position.xz;
+    } else if face == 2u {
+        return -position.xy;
+    } else if face == 3u {
+        return vec2(position.x, -position.y);
+    } else if face == 4u {
+        return vec2(position.z, -position.y);
+    }
+    return vec2(-position.z, -position.y);
+}
+
+@vertex
+fn vertex(vertex: PackedVertex) -> VertexOutput {
+    var normals = array<vec3<f32>, 6>(
+        vec3(0.0, -1.0, 0.0),
+        vec3(0.0, 1.0, 0.0),
+        vec3(0.0, 0.0, -1.0),
+        vec3(0.0, 0.0, 1.0),
+        vec3(-1.0, 0.0, 0.0),
+        vec3(1.0, 0.0, 0.0),
+    );
+    var ao_curve = array<f32, 4>(0.35, 0.55, 0.75, 1.0);
+
+    let position = vec3<f32>((vec3(vertex.packed.x) >> vec3(0u, 5u, 10u)) & vec3(31u));
+    let face = (vertex.packed.x >> 15u) & 7u;
+    let ao = (vertex.packed.x >> 18u) & 3u;
+    let layer = vertex.packed.x >> 20u;
+
+    var out: VertexOutput;
+    let model = mesh_functions::get_model_matrix(vertex.instance_index);
+    out.world_position = mesh_functions::mesh_position_local_to_world(model, vec4(position, 1.0));
+    out.position = mesh_functions::mesh_position_local_to_clip(model, vec4(position, 1.0));
+#ifdef DEPTH_CLAMP_ORTHO
+    out.clip_position_unclamped = out.position;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+
+#ifdef PREPASS_PIPELINE
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(normals[face], vertex.instance_index);
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    out.previous_world_position = mesh_functions::mesh_position_local_to_world(
+        mesh_functions::get_previous_model_matrix(vertex.instance_index),
+        vec4(position, 1.0)
+    );
+#endif
+#else
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(normals[face], vertex.instance_index);
+#endif
+
+    out.uv = voxel_uv(position, face);
+    out.uv_b = vec2(ao_curve[ao], f32(layer));
+    out.color = unpack4x8unorm(vertex.packed.y);
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+    return out;
+}
@@ -0,0 +1,168 @@
+use bevy::math::IVec3;
+use bevy::render::color::Color;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Biome {
+    Plains,
+    Forest,
+    Desert,
+    Tundra,
+    Mountains,
+}
+
+impl Biome {
+    pub const ALL: [Biome; 5] = [Biome::Plains, Biome::Forest, Biome::Desert, Biome::Tundra, Biome::Mountains];
+
+    /// The temperature and humidity this biome is most likely at, both roughly in -1..1.
+    fn climate(self) -> (f64, f64) {
+        return match self {
+            Biome::Plains => (0.0, 0.0),
+            Biome::Forest => (0.15, 0.45),
+            Biome::Desert => (0.5, -0.4),
+            Biome::Tundra => (-0.5, 0.1),
+            Biome::Mountains => (-0.2, -0.35),
+        };
+    }
+
+    /// Name of the block covering the ground.
+    pub fn surface_block(self) -> &'static str {
+        return match self {
+            Biome::Plains | Biome::Forest => "grass",
+            Biome::Desert => "sand",
+            Biome::Tundra => "snow",
+            Biome::Mountains => "stone",
+        };
+    }
+
+    /// Name of the block in the layers between the surface and the stone below.
+    pub fn filler_block(self) -> &'static str {
+        return match self {
+            Biome::Plains | Biome::Forest | Biome::Tundra => "dirt",
+            Biome::Desert => "sand",
+            Biome::Mountains => "stone",
+        };
+    }
+
+    /// How strongly the terrain height varies, relative to the generator's amplitude.
+    pub fn height_scale(self) -> f64 {
+        return match self {
+            Biome::Plains => 0.4,
+            Biome::Forest => 0.7,
+            Biome::Desert => 0.3,
+            Biome::Tundra => 0.6,
+            Biome::Mountains => 2.0,
+        };
+    }
+
+    /// How the ground below this biome is hollowed out.
+    pub fn caves(self) -> CaveSettings {
+        return match self {
+            Biome::Plains => CaveSettings { tunnel_width: 0.06, overhangs: 0.0 },
+            Biome::Forest => CaveSettings { tunnel_width: 0.07, overhangs: 0.15 },
+            Biome::Desert => CaveSettings { tunnel_width: 0.04, overhangs: 0.05 },
+            Biome::Tundra => CaveSettings { tunnel_width: 0.05, overhangs: 0.15 },
+            Biome::Mountains => CaveSettings { tunnel_width: 0.09, overhangs: 0.3 },
+        };
+    }
+
+    /// Structure templates placed on the surface, with the chance of each placement attempt picking them.
+    pub fn features(self) -> &'static [(&'static str, f64)] {
+        return match self {
+            Biome::Plains => &[("oak_tree", 0.05), ("boulder", 0.02), ("ruin", 0.005)],
+            Biome::Forest => &[("oak_tree", 0.5), ("spruce_tree", 0.1)],
+            Biome::Desert => &[("boulder", 0.03), ("ruin", 0.01)],
+            Biome::Tundra => &[("spruce_tree", 0.2), ("boulder", 0.02)],
+            Biome::Mountains => &[("boulder", 0.08), ("spruce_tree", 0.03)],
+        };
+    }
+
+    /// The color of tinted faces, like the top of grass, whose textures are grayscale so this is their only color.
+    pub fn tint(self) -> Color {
+        return match self {
+            Biome::Plains => Color::rgb(0.47, 0.78, 0.26),
+            Biome::Forest => Color::rgb(0.35, 0.66, 0.2),
+            Biome::Desert => Color::rgb(0.66, 0.7, 0.32),
+            Biome::Tundra => Color::rgb(0.45, 0.68, 0.5),
+            Biome::Mountains => Color::rgb(0.44, 0.65, 0.36),
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CaveSettings {
+    /// How close to zero both tunnel noises must be for a block to be carved. 0 disables tunnels.
+    pub tunnel_width: f64,
+    /// How much of the ground just below the surface is hollowed out into overhangs and arches, in 0..1.
+    pub overhangs: f64,
+}
+
+/// Assigns every column of the world a biome from seeded temperature and humidity noise.
+pub struct BiomeMap {
+    temperature: Fbm<Perlin>,
+    humidity: Fbm<Perlin>,
+}
+
+impl BiomeMap {
+    /// How far apart, in climate, two biomes can be while still blending their terrain heights.
+    const BLEND_WIDTH: f64 = 0.15;
+
+    pub fn new(seed: u32) -> Self {
+        Self {
+            temperature: Fbm::<Perlin>::new(seed.wrapping_add(1)).set_octaves(3).set_frequency(1.0 / 512.0),
+            humidity: Fbm::<Perlin>::new(seed.wrapping_add(2)).set_octaves(3).set_frequency(1.0 / 512.0),
+        }
+    }
+
+    /// Temperature and humidity of the column, both roughly in -1..1.
+    pub fn climate_at(&self, x: i32, z: i32) -> (f64, f64) {
+        let point = [x as f64, z as f64];
+        return (self.temperature.get(point), self.humidity.get(point));
+    }
+
+    /// The biome of the column containing the position. Biomes only vary horizontally.
+    pub fn biome_at(&self, pos: IVec3) -> Biome {
+        let climate = self.climate_at(pos.x, pos.z);
+        return *Biome::ALL.iter()
+            .min_by(|a, b| climate_distance(a.climate(), climate).total_cmp(&climate_distance(b.climate(), climate)))
+            .unwrap();
+    }
+
+    /// The terrain height scale of the column, blended between nearby biomes so there are no cliffs at their borders.
+    pub fn height_scale_at(&self, x: i32, z: i32) -> f64 {
+        return self.blend(x, z, Biome::height_scale);
+    }
+
+    /// How the ground below the column is hollowed out, blended between nearby biomes so tunnels narrow gradually
+    /// instead of ending in a wall at their borders.
+    pub fn caves_at(&self, x: i32, z: i32) -> CaveSettings {
+        return CaveSettings {
+            tunnel_width: self.blend(x, z, |biome| biome.caves().tunnel_width),
+            overhangs: self.blend(x, z, |biome| biome.caves().overhangs),
+        };
+    }
+
+    /// Averages a value over every biome, weighted by how close their climate is to that of the column.
+    fn blend(&self, x: i32, z: i32, value: impl Fn(Biome) -> f64) -> f64 {
+        let climate = self.climate_at(x, z);
+        let mut total = 0.0;
+        let mut weights = 0.0;
+        for biome in Biome::ALL {
+            let weight = (-climate_distance(biome.climate(), climate) / (BiomeMap::BLEND_WIDTH * BiomeMap::BLEND_WIDTH)).exp();
+            total += value(biome) * weight;
+            weights += weight;
+        }
+        return total / weights;
+    }
+}
+
+impl Default for BiomeMap {
+    fn default() -> Self {
+        BiomeMap::new(0)
+    }
+}
+
+/// Squared distance between two climates.
+fn climate_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    return (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2);
+}
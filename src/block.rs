@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use bevy::app::{App, Plugin, Startup, Update};
+use bevy::asset::{Asset, AssetApp, AssetEvent, AssetLoader, Assets, AssetServer, AsyncReadExt, Handle, LoadContext, LoadedFolder, LoadState};
+use bevy::asset::io::Reader;
+use bevy::log::{info, warn};
+use bevy::prelude::{Color, Commands, Event, EventReader, EventWriter, IntoSystemConfigs, Res, ResMut, Resource};
+use bevy::reflect::TypePath;
+use bevy::utils::BoxedFuture;
+use serde::Deserialize;
+
+use crate::world::Face;
+
+/// Identifies a block type in the [`BlockRegistry`]. Id 0 is always air.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct BlockId(pub u16);
+
+impl BlockId {
+    pub const AIR: BlockId = BlockId(0);
+}
+
+/// Texture paths for each face of a block. More specific entries take precedence over `all`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BlockTextures {
+    pub all: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    pub side: Option<String>,
+}
+
+impl BlockTextures {
+    pub fn get(&self, face: Face) -> Option<&String> {
+        let specific = match face {
+            Face::Up => &self.top,
+            Face::Down => &self.bottom,
+            _ => &self.side,
+        };
+        return specific.as_ref().or(self.all.as_ref());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockProperties {
+    pub name: String,
+    /// Whether the block fills its whole cell.
+    pub solid: bool,
+    /// Whether the block hides the faces of the blocks behind it.
+    pub opaque: bool,
+    /// Whether the block is rendered but can be seen through, like glass or leaves.
+    pub transparent: bool,
+    pub collidable: bool,
+    pub hardness: f32,
+    pub light_emission: u8,
+    /// The color of faces without a texture.
+    pub color: Color,
+    /// Whether the top face is colored by the biome, like grass. Its texture should be grayscale.
+    pub tinted: bool,
+    pub textures: BlockTextures,
+    /// Scales how quickly bodies standing on the block slow down. Low for ice.
+    pub friction: f32,
+    /// How much of its falling speed a body keeps, bouncing back up, when it lands on the block.
+    pub restitution: f32,
+    /// Scales how fast bodies standing on the block move. Low for mud.
+    pub speed: f32,
+    /// Set for each level of a fluid, which are registered as separate blocks.
+    pub fluid: Option<FluidLevel>,
+}
+
+/// How full a fluid block is. Level `max_level` is the source, which never drains, and it is stored under the id of the
+/// fluid's definition. Each lower level uses the next id.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FluidLevel {
+    pub source: BlockId,
+    pub level: u8,
+    pub max_level: u8,
+}
+
+impl FluidLevel {
+    pub fn is_source(&self) -> bool {
+        return self.level == self.max_level;
+    }
+
+    /// The block of the same fluid at another level.
+    pub fn block(&self, level: u8) -> BlockId {
+        return BlockId(self.source.0 + (self.max_level - level) as u16);
+    }
+}
+
+impl BlockProperties {
+    /// A solid, opaque, collidable cube.
+    pub fn solid(name: &str, color: Color) -> Self {
+        Self {
+            name: name.to_string(),
+            solid: true,
+            opaque: true,
+            transparent: false,
+            collidable: true,
+            hardness: 1.0,
+            light_emission: 0,
+            color,
+            tinted: false,
+            textures: BlockTextures::default(),
+            friction: 1.0,
+            restitution: 0.0,
+            speed: 1.0,
+            fluid: None,
+        }
+    }
+
+    /// An invisible block with no collision.
+    pub fn empty(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            solid: false,
+            opaque: false,
+            transparent: false,
+            collidable: false,
+            hardness: 0.0,
+            light_emission: 0,
+            color: Color::NONE,
+            tinted: false,
+            textures: BlockTextures::default(),
+            friction: 1.0,
+            restitution: 0.0,
+            speed: 1.0,
+            fluid: None,
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        return self.opaque || self.transparent;
+    }
+
+    /// Whether the block can be selected and placed by the player.
+    pub fn is_placeable(&self) -> bool {
+        return match self.fluid {
+            Some(fluid) => fluid.is_source(),
+            None => self.solid,
+        };
+    }
+}
+
+/// All registered block types, indexed by [`BlockId`].
+///
+/// Cloning is cheap so the world and background mesh tasks can hold their own copy.
+#[derive(Debug, Clone, Resource)]
+pub struct BlockRegistry {
+    blocks: Arc<Vec<BlockProperties>>,
+    ids: Arc<HashMap<String, BlockId>>,
+    textures: Arc<Vec<String>>,
+    texture_layers: Arc<Vec<[u32; 6]>>,
+}
+
+impl BlockRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            blocks: Arc::new(Vec::new()),
+            ids: Arc::new(HashMap::new()),
+            textures: Arc::new(Vec::new()),
+            texture_layers: Arc::new(Vec::new()),
+        };
+        registry.register(BlockProperties::empty("air"));
+        return registry;
+    }
+
+    /// The blocks that exist even if no definitions could be loaded.
+    pub fn builtin() -> Self {
+        let mut registry = BlockRegistry::new();
+        registry.register(BlockProperties::solid("stone", Color::GRAY));
+        return registry;
+    }
+
+    pub fn register(&mut self, properties: BlockProperties) -> BlockId {
+        let id = BlockId(self.blocks.len() as u16);
+        self.insert(id, properties);
+        return id;
+    }
+
+    /// Registers a block under a specific id, replacing any block that already uses it.
+    pub fn insert(&mut self, id: BlockId, properties: BlockProperties) {
+        let blocks = Arc::make_mut(&mut self.blocks);
+        while blocks.len() <= id.0 as usize {
+            blocks.push(BlockProperties::empty(""));
+        }
+        let ids = Arc::make_mut(&mut self.ids);
+        ids.remove(&blocks[id.0 as usize].name);
+        ids.insert(properties.name.clone(), id);
+        blocks[id.0 as usize] = properties;
+        self.update_texture_layers();
+    }
+
+    /// Assigns every distinct texture path a layer in the block texture array. Layer 0 is left blank for untextured faces.
+    fn update_texture_layers(&mut self) {
+        let mut textures: Vec<String> = Vec::new();
+        let mut texture_layers: Vec<[u32; 6]> = Vec::with_capacity(self.blocks.len());
+        for properties in self.blocks.iter() {
+            let mut layers = [0; 6];
+            for face in Face::ALL {
+                if let Some(path) = properties.textures.get(face) {
+                    let index = match textures.iter().position(|texture| texture == path) {
+                        Some(index) => index,
+                        None => {
+                            textures.push(path.clone());
+                            textures.len() - 1
+                        }
+                    };
+                    layers[face as usize] = index as u32 + 1;
+                }
+            }
+            texture_layers.push(layers);
+        }
+        self.textures = Arc::new(textures);
+        self.texture_layers = Arc::new(texture_layers);
+    }
+
+    /// Returns the properties of the block, falling back to air for unknown ids.
+    pub fn get(&self, id: BlockId) -> &BlockProperties {
+        return self.blocks.get(id.0 as usize).unwrap_or(&self.blocks[BlockId::AIR.0 as usize]);
+    }
+
+    pub fn id(&self, name: &str) -> Option<BlockId> {
+        return self.ids.get(name).copied();
+    }
+
+    /// The texture array layer to use for a face of the block.
+    pub fn texture_layer(&self, id: BlockId, face: Face) -> u32 {
+        return self.texture_layers.get(id.0 as usize).map_or(0, |layers| layers[face as usize]);
+    }
+
+    /// Texture paths in layer order, starting at layer 1.
+    pub fn textures(&self) -> &[String] {
+        return &self.textures;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.blocks.len();
+    }
+
+    /// Ids whose properties differ between the two registries.
+    pub fn changed_ids(&self, other: &BlockRegistry) -> Vec<BlockId> {
+        return (0..self.len().max(other.len()) as u16)
+            .map(BlockId)
+            .filter(|id| self.blocks.get(id.0 as usize) != other.blocks.get(id.0 as usize))
+            .collect();
+    }
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        BlockRegistry::new()
+    }
+}
+
+/// A block type as written in a `*.block.ron` file under `assets/blocks`.
+#[derive(Debug, Clone, Asset, TypePath, Deserialize)]
+pub struct BlockDefinition {
+    pub id: u16,
+    pub name: String,
+    #[serde(default)]
+    pub textures: BlockTextures,
+    #[serde(default = "default_true")]
+    pub solid: bool,
+    pub opaque: Option<bool>,
+    #[serde(default)]
+    pub transparent: bool,
+    pub collidable: Option<bool>,
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    #[serde(default)]
+    pub emission: u8,
+    #[serde(default = "default_color")]
+    pub color: [f32; 3],
+    #[serde(default)]
+    pub tinted: bool,
+    #[serde(default = "default_one")]
+    pub friction: f32,
+    #[serde(default)]
+    pub restitution: f32,
+    #[serde(default = "default_one")]
+    pub speed: f32,
+    /// Makes the block a fluid with this many levels, taking up as many ids starting at `id`.
+    #[serde(default)]
+    pub fluid_levels: u8,
+}
+
+impl BlockDefinition {
+    /// The ids the block takes up, one for each level of a fluid.
+    fn ids(&self) -> Range<u32> {
+        return self.id as u32..self.id as u32 + self.fluid_levels.max(1) as u32;
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_hardness() -> f32 {
+    1.0
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+fn default_color() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+impl From<&BlockDefinition> for BlockProperties {
+    fn from(definition: &BlockDefinition) -> Self {
+        Self {
+            name: definition.name.clone(),
+            solid: definition.solid,
+            opaque: definition.opaque.unwrap_or(definition.solid && !definition.transparent),
+            transparent: definition.transparent,
+            collidable: definition.collidable.unwrap_or(definition.solid),
+            hardness: definition.hardness,
+            light_emission: definition.emission,
+            color: Color::rgb(definition.color[0], definition.color[1], definition.color[2]),
+            tinted: definition.tinted,
+            textures: definition.textures.clone(),
+            friction: definition.friction,
+            restitution: definition.restitution,
+            speed: definition.speed,
+            fluid: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BlockDefinitionLoader;
+
+impl AssetLoader for BlockDefinitionLoader {
+    type Asset = BlockDefinition;
+    type Settings = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn load<'a>(&'a self, reader: &'a mut Reader, _settings: &'a (), _load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            return Ok(ron::de::from_bytes::<BlockDefinition>(&bytes)?);
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["block.ron"]
+    }
+}
+
+/// Sent whenever the [`BlockRegistry`] is rebuilt from the block definitions.
+#[derive(Debug, Event)]
+pub struct BlockRegistryChanged {
+    pub changed: Vec<BlockId>,
+}
+
+/// Inserted once every block definition has loaded, or failed to, and the registry has been rebuilt from them.
+#[derive(Debug, Resource)]
+pub struct BlockDefinitionsLoaded;
+
+/// Keeps the definitions loaded, and watched for changes, for as long as the app runs.
+#[derive(Resource)]
+struct BlockDefinitionFolder {
+    handle: Handle<LoadedFolder>,
+}
+
+/// Loads block definitions from `assets/blocks` into the [`BlockRegistry`] and keeps it up to date
+/// as the files change.
+pub struct BlockPlugin;
+
+impl Plugin for BlockPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BlockRegistry::builtin())
+            .init_asset::<BlockDefinition>()
+            .init_asset_loader::<BlockDefinitionLoader>()
+            .add_event::<BlockRegistryChanged>()
+            .add_systems(Startup, load_block_definitions)
+            .add_systems(Update, (rebuild_registry, mark_definitions_loaded).chain());
+    }
+}
+
+fn load_block_definitions(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(BlockDefinitionFolder {
+        handle: asset_server.load_folder("blocks")
+    });
+}
+
+fn mark_definitions_loaded(mut commands: Commands, folder: Option<Res<BlockDefinitionFolder>>, loaded: Option<Res<BlockDefinitionsLoaded>>, asset_server: Res<AssetServer>) {
+    let Some(folder) = folder else {
+        return;
+    };
+    if loaded.is_some() {
+        return;
+    }
+    let failed = asset_server.get_load_state(&folder.handle) == Some(LoadState::Failed);
+    if failed || asset_server.is_loaded_with_dependencies(&folder.handle) {
+        commands.insert_resource(BlockDefinitionsLoaded);
+    }
+}
+
+fn rebuild_registry(
+    mut events: EventReader<AssetEvent<BlockDefinition>>,
+    definitions: Res<Assets<BlockDefinition>>,
+    mut registry: ResMut<BlockRegistry>,
+    mut changed_events: EventWriter<BlockRegistryChanged>,
+) {
+    if events.read().count() == 0 {
+        return;
+    }
+
+    let mut rebuilt = BlockRegistry::builtin();
+    let mut definitions: Vec<&BlockDefinition> = definitions.iter().map(|(_, definition)| definition).collect();
+    // Asset order changes between runs, so ties are broken by name to always give the same definitions the same ids
+    definitions.sort_by(|a, b| a.id.cmp(&b.id).then_with(|| a.name.cmp(&b.name)));
+    // Where the ids taken so far end, and the block that took the last of them
+    let mut taken: Option<(u32, &str)> = None;
+    for definition in definitions {
+        if definition.id == BlockId::AIR.0 {
+            warn!("Block '{}' cannot use id 0, which is reserved for air", definition.name);
+            continue;
+        }
+        let ids = definition.ids();
+        if ids.end > u16::MAX as u32 + 1 {
+            warn!("Fluid '{}' needs ids {} to {}, past the largest block id", definition.name, ids.start, ids.end - 1);
+            continue;
+        }
+        if let Some((_, other)) = taken.filter(|(end, _)| *end > ids.start) {
+            warn!("Block '{}' with id {} overlaps the ids of block '{}' and is skipped", definition.name, definition.id, other);
+            continue;
+        }
+        taken = Some((ids.end, &definition.name));
+
+        let properties = BlockProperties::from(definition);
+        if definition.fluid_levels == 0 {
+            rebuilt.insert(BlockId(definition.id), properties);
+            continue;
+        }
+        // Every level of a fluid is its own block, named after the level except for the source
+        for level in 1..=definition.fluid_levels {
+            let fluid = FluidLevel { source: BlockId(definition.id), level, max_level: definition.fluid_levels };
+            let mut properties = properties.clone();
+            if !fluid.is_source() {
+                properties.name = format!("{}_{}", definition.name, level);
+            }
+            properties.fluid = Some(fluid);
+            rebuilt.insert(fluid.block(level), properties);
+        }
+    }
+
+    let changed = rebuilt.changed_ids(&registry);
+    if changed.is_empty() {
+        return;
+    }
+    info!("Reloaded block definitions, {} block(s) changed", changed.len());
+    *registry = rebuilt;
+    changed_events.send(BlockRegistryChanged { changed });
+}
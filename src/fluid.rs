@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use bevy::app::{App, Plugin, Update};
+use bevy::math::IVec3;
+use bevy::prelude::{Res, ResMut, Resource, Time, Timer, TimerMode};
+
+use crate::block::{BlockId, FluidLevel};
+use crate::voxel_mesher::ClientWorld;
+use crate::world::{BlockGetter, VoxelWorld};
+
+/// Lets fluids spread from their sources and drain away once they lose them.
+pub struct FluidPlugin;
+
+impl Plugin for FluidPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FluidSimulation>()
+            .add_systems(Update, tick_fluids);
+    }
+}
+
+/// Paces the fluid updates the world schedules, so fluids spread one block per tick.
+#[derive(Resource)]
+pub struct FluidSimulation {
+    timer: Timer,
+}
+
+impl FluidSimulation {
+    pub const TICK_INTERVAL: Duration = Duration::from_millis(250);
+}
+
+impl Default for FluidSimulation {
+    fn default() -> Self {
+        Self {
+            timer: Timer::new(FluidSimulation::TICK_INTERVAL, TimerMode::Repeating),
+        }
+    }
+}
+
+/// Updates every fluid block scheduled since the last tick. The changes schedule their neighbours for the next one.
+fn tick_fluids(time: Res<Time>, client_world: Res<ClientWorld>, mut simulation: ResMut<FluidSimulation>) {
+    if !simulation.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let mut world = client_world.0.write().unwrap();
+    for pos in world.take_fluid_updates() {
+        update_fluid(&mut world, pos);
+    }
+}
+
+const HORIZONTAL: [IVec3; 4] = [IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z];
+
+fn update_fluid(world: &mut VoxelWorld, pos: IVec3) {
+    let Some(fluid) = world.get_properties(pos).fluid else {
+        return;
+    };
+
+    // Flowing fluid is one level below the highest neighbour feeding it, and falling fluid is kept full by the fluid
+    // above. Without either it drains away.
+    if !fluid.is_source() {
+        let supported = if fluid_at(world, pos + IVec3::Y, fluid.source).is_some() {
+            fluid.max_level - 1
+        } else {
+            HORIZONTAL.iter()
+                .filter_map(|offset| fluid_at(world, pos + *offset, fluid.source))
+                .map(|neighbour| neighbour.level.saturating_sub(1))
+                .max()
+                .unwrap_or(0)
+        };
+        if supported != fluid.level {
+            world.set_block(pos, if supported == 0 { BlockId::AIR } else { fluid.block(supported) });
+            return;
+        }
+    }
+
+    // Falling takes priority, only fluid resting on something spreads sideways
+    let below = pos + IVec3::NEG_Y;
+    if can_flow_into(world, below, fluid, fluid.max_level - 1) {
+        world.set_block(below, fluid.block(fluid.max_level - 1));
+        return;
+    }
+    if fluid_at(world, below, fluid.source).is_some() || fluid.level <= 1 {
+        return;
+    }
+    for offset in HORIZONTAL {
+        if can_flow_into(world, pos + offset, fluid, fluid.level - 1) {
+            world.set_block(pos + offset, fluid.block(fluid.level - 1));
+        }
+    }
+}
+
+/// The level of the fluid at the position, if it is the same fluid.
+fn fluid_at(world: &VoxelWorld, pos: IVec3, source: BlockId) -> Option<FluidLevel> {
+    return world.get_properties(pos).fluid.filter(|fluid| fluid.source == source);
+}
+
+/// Whether the fluid can spread into the position at the given level, replacing empty space or a lower level of itself.
+/// Fluids never spread into chunks that aren't loaded.
+fn can_flow_into(world: &VoxelWorld, pos: IVec3, fluid: FluidLevel, level: u8) -> bool {
+    if !world.is_loaded(VoxelWorld::chunk_pos(pos)) {
+        return false;
+    }
+    let properties = world.get_properties(pos);
+    return match properties.fluid {
+        Some(other) => other.source == fluid.source && other.level < level,
+        None => !properties.solid && !properties.collidable,
+    };
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bevy::log::warn;
+use bevy::math::IVec3;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+
+use crate::biome::{Biome, BiomeMap, CaveSettings};
+use crate::block::{BlockId, BlockRegistry};
+use crate::structure::StructureTemplate;
+use crate::world::{RenderChunk, VoxelWorld};
+
+/// Produces the initial contents of chunks that have never been saved.
+///
+/// Chunks are generated in the background in any order, so the result must only depend on the chunk position and the
+/// generator's own settings.
+pub trait WorldGenerator: Send + Sync {
+    fn generate_chunk(&self, chunk_pos: IVec3, registry: &BlockRegistry) -> RenderChunk;
+}
+
+/// The terrain columns of the chunk being generated, shared with every stage that runs after the heightmap.
+pub struct ChunkColumns {
+    pub origin: IVec3,
+    heights: [i32; VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE],
+    biomes: [Biome; VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE],
+}
+
+impl ChunkColumns {
+    /// The y coordinate of the topmost terrain block of the column, before any stage ran. `x` and `z` are relative to
+    /// the chunk origin.
+    pub fn height(&self, x: i32, z: i32) -> i32 {
+        return self.heights[(x + z * VoxelWorld::CHUNK_SIZE as i32) as usize];
+    }
+
+    pub fn biome(&self, x: i32, z: i32) -> Biome {
+        return self.biomes[(x + z * VoxelWorld::CHUNK_SIZE as i32) as usize];
+    }
+}
+
+/// A pass over a chunk after its terrain has been filled in from the heightmap.
+pub trait GenerationStage: Send + Sync {
+    fn apply(&self, chunk: &mut RenderChunk, columns: &ChunkColumns, terrain: &TerrainGenerator, registry: &BlockRegistry);
+}
+
+/// Lets a stage also be used by later ones, like the caves that [`FeaturePlacer`] keeps structures away from.
+impl<T: GenerationStage + ?Sized> GenerationStage for Arc<T> {
+    fn apply(&self, chunk: &mut RenderChunk, columns: &ChunkColumns, terrain: &TerrainGenerator, registry: &BlockRegistry) {
+        (**self).apply(chunk, columns, terrain, registry);
+    }
+}
+
+/// Hollows out connected cave tunnels where two 3D noise fields are both close to zero, and overhangs where a third
+/// one peaks just below the surface. How much is carved is blended from the biomes around each column.
+pub struct CaveCarver {
+    tunnels: [Perlin; 2],
+    hollows: Fbm<Perlin>,
+    /// How many blocks below the surface overhangs reach.
+    pub overhang_depth: i32,
+}
+
+impl CaveCarver {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            tunnels: [Perlin::new(seed.wrapping_add(10)), Perlin::new(seed.wrapping_add(11))],
+            hollows: Fbm::<Perlin>::new(seed.wrapping_add(12)).set_octaves(2).set_frequency(1.0 / 24.0),
+            overhang_depth: 8,
+        }
+    }
+
+    fn is_carved(&self, pos: IVec3, depth: i32, settings: CaveSettings) -> bool {
+        if depth < self.overhang_depth && settings.overhangs > 0.0 {
+            let hollow = self.hollows.get([pos.x as f64, pos.y as f64 * 1.5, pos.z as f64]);
+            if hollow > 0.7 - settings.overhangs {
+                return true;
+            }
+        }
+
+        // The zero surfaces of two noise fields meet along long winding curves, which become the tunnels. Squashing y
+        // keeps them from getting too steep to walk through. The offset moves the noise lattice, where both fields are
+        // always zero, off the block grid.
+        let point = [pos.x as f64 / 48.0 + 0.37, pos.y as f64 / 32.0 + 0.37, pos.z as f64 / 48.0 + 0.37];
+        return self.tunnels.iter().all(|tunnel| tunnel.get(point).abs() < settings.tunnel_width);
+    }
+}
+
+impl GenerationStage for CaveCarver {
+    fn apply(&self, chunk: &mut RenderChunk, columns: &ChunkColumns, terrain: &TerrainGenerator, _registry: &BlockRegistry) {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        for z in 0..SIZE {
+            for x in 0..SIZE {
+                let height = columns.height(x, z);
+                if height < columns.origin.y {
+                    continue;
+                }
+                let settings = terrain.biomes.caves_at(columns.origin.x + x, columns.origin.z + z);
+                for y in 0..SIZE {
+                    let pos = columns.origin + IVec3::new(x, y, z);
+                    if pos.y > height || chunk.get_block(pos) == BlockId::AIR {
+                        continue;
+                    }
+                    if self.is_carved(pos, height - pos.y, settings) {
+                        chunk.set_block(pos, BlockId::AIR);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Places structure templates on the surface, picked by the biome of the column they stand on.
+///
+/// Placements are rolled per chunk column from a seed derived from its position, and every chunk also replays the
+/// placements of the columns around it, so structures straddling chunk borders come out whole no matter which of the
+/// chunks is generated first.
+pub struct FeaturePlacer {
+    seed: u64,
+    templates: HashMap<&'static str, StructureTemplate>,
+    /// How many chunk columns away a structure can still reach into a chunk.
+    reach: i32,
+    /// How many placements are attempted per chunk column.
+    pub attempts: u32,
+    /// The caves carved before structures are placed, so none of them are left floating above a cave opening.
+    caves: Option<Arc<CaveCarver>>,
+    /// Set once a template referring to an unknown block has been reported.
+    warned_unknown: AtomicBool,
+}
+
+impl FeaturePlacer {
+    pub fn new(seed: u32, templates: Vec<StructureTemplate>) -> Self {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        let extent = templates.iter()
+            .map(|template| template.bounds())
+            .map(|(min, max)| min.x.abs().max(min.z.abs()).max(max.x).max(max.z))
+            .max()
+            .unwrap_or(0);
+        Self {
+            seed: seed as u64,
+            templates: templates.into_iter().map(|template| (template.name, template)).collect(),
+            reach: (extent + SIZE - 1) / SIZE,
+            attempts: 8,
+            caves: None,
+            warned_unknown: AtomicBool::new(false),
+        }
+    }
+
+    /// Skips placements standing on ground that the carver hollowed out.
+    pub fn with_caves(mut self, caves: Arc<CaveCarver>) -> Self {
+        self.caves = Some(caves);
+        return self;
+    }
+}
+
+impl GenerationStage for FeaturePlacer {
+    fn apply(&self, chunk: &mut RenderChunk, columns: &ChunkColumns, terrain: &TerrainGenerator, registry: &BlockRegistry) {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        let (chunk_min, chunk_max) = (columns.origin, columns.origin + IVec3::splat(SIZE - 1));
+        for column_z in -self.reach..=self.reach {
+            for column_x in -self.reach..=self.reach {
+                let column_origin = columns.origin + IVec3::new(column_x, 0, column_z) * SIZE;
+                let mut random = SplitMix64::new(self.seed ^ column_seed(column_origin.x, column_origin.z));
+                for _ in 0..self.attempts {
+                    // Always draw the same numbers so one attempt never shifts the rolls of the next
+                    let x = column_origin.x + random.range(SIZE);
+                    let z = column_origin.z + random.range(SIZE);
+                    let roll = random.next_f64();
+
+                    let biome = terrain.biomes.biome_at(IVec3::new(x, 0, z));
+                    let mut chance = 0.0;
+                    let Some(template) = biome.features().iter()
+                        .find(|(_, feature_chance)| {
+                            chance += feature_chance;
+                            return roll < chance;
+                        })
+                        .and_then(|(name, _)| self.templates.get(name)) else {
+                        continue;
+                    };
+
+                    let origin = IVec3::new(x, terrain.height_at(x, z), z);
+                    let (min, max) = template.bounds();
+                    if (origin + max).cmplt(chunk_min).any() || (origin + min).cmpgt(chunk_max).any() {
+                        continue;
+                    }
+                    // Carving only depends on the position, so this holds even when the origin is in another chunk
+                    if self.caves.as_ref().is_some_and(|caves| caves.is_carved(origin, 0, terrain.biomes.caves_at(x, z))) {
+                        continue;
+                    }
+                    for (offset, name) in &template.blocks {
+                        let pos = origin + *offset;
+                        if !pos.cmpge(chunk_min).all() || !pos.cmple(chunk_max).all() {
+                            continue;
+                        }
+                        // Writing air instead would cut holes into the terrain
+                        let Some(block) = registry.id(name) else {
+                            if !self.warned_unknown.swap(true, Ordering::Relaxed) {
+                                warn!("Structure '{}' uses unknown block '{}', which is left out", template.name, name);
+                            }
+                            continue;
+                        };
+                        chunk.set_block(pos, block);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Mixes the position of a chunk column into a seed, so neighbouring columns get unrelated placements.
+fn column_seed(x: i32, z: i32) -> u64 {
+    return (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ (z as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+}
+
+/// A small deterministic random number generator, so the same seed always places the same structures.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return z ^ (z >> 31);
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    /// A number in 0..end.
+    fn range(&mut self, end: i32) -> i32 {
+        return (self.next_u64() % end as u64) as i32;
+    }
+}
+
+/// Hills from a seeded fractal noise heightmap, shaped and covered according to the biome of each column.
+pub struct TerrainGenerator {
+    heightmap: Fbm<Perlin>,
+    pub biomes: Arc<BiomeMap>,
+    pub base_height: i32,
+    pub amplitude: f64,
+    pub filler_depth: i32,
+    /// Run in order on every generated chunk.
+    pub stages: Vec<Box<dyn GenerationStage>>,
+}
+
+impl TerrainGenerator {
+    pub fn new(seed: u32, biomes: Arc<BiomeMap>) -> Self {
+        let caves = Arc::new(CaveCarver::new(seed));
+        Self {
+            heightmap: Fbm::<Perlin>::new(seed).set_octaves(5).set_frequency(1.0 / 128.0),
+            biomes,
+            base_height: -4,
+            amplitude: 24.0,
+            filler_depth: 3,
+            stages: vec![
+                Box::new(caves.clone()),
+                Box::new(FeaturePlacer::new(seed, StructureTemplate::all()).with_caves(caves)),
+            ],
+        }
+    }
+
+    /// The y coordinate of the topmost block of the column.
+    pub fn height_at(&self, x: i32, z: i32) -> i32 {
+        let amplitude = self.amplitude * self.biomes.height_scale_at(x, z);
+        return self.base_height + (self.heightmap.get([x as f64, z as f64]) * amplitude).round() as i32;
+    }
+}
+
+impl WorldGenerator for TerrainGenerator {
+    fn generate_chunk(&self, chunk_pos: IVec3, registry: &BlockRegistry) -> RenderChunk {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        // Blocks missing from the registry fall back to stone, which is always registered
+        let stone = registry.id("stone").unwrap_or(BlockId::AIR);
+        let block = |name: &str| registry.id(name).unwrap_or(stone);
+
+        let origin = chunk_pos * SIZE;
+        let mut columns = ChunkColumns {
+            origin,
+            heights: [0; (SIZE * SIZE) as usize],
+            biomes: [Biome::Plains; (SIZE * SIZE) as usize],
+        };
+        for z in 0..SIZE {
+            for x in 0..SIZE {
+                columns.heights[(x + z * SIZE) as usize] = self.height_at(origin.x + x, origin.z + z);
+                columns.biomes[(x + z * SIZE) as usize] = self.biomes.biome_at(origin + IVec3::new(x, 0, z));
+            }
+        }
+
+        let mut chunk = self.fill_terrain(&columns, stone, block);
+        for stage in &self.stages {
+            stage.apply(&mut chunk, &columns, self, registry);
+        }
+        return chunk;
+    }
+}
+
+impl TerrainGenerator {
+    fn fill_terrain(&self, columns: &ChunkColumns, stone: BlockId, block: impl Fn(&str) -> BlockId) -> RenderChunk {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        let origin = columns.origin;
+        let (lowest, highest) = (*columns.heights.iter().min().unwrap(), *columns.heights.iter().max().unwrap());
+        if origin.y > highest {
+            return RenderChunk::create_solid(BlockId::AIR);
+        }
+        if origin.y + SIZE <= lowest - self.filler_depth {
+            return RenderChunk::create_solid(stone);
+        }
+
+        let mut chunk = RenderChunk::create_solid(BlockId::AIR);
+        for z in 0..SIZE {
+            for x in 0..SIZE {
+                let height = columns.height(x, z);
+                let biome = columns.biome(x, z);
+                let (surface, filler) = (block(biome.surface_block()), block(biome.filler_block()));
+                for y in 0..SIZE {
+                    let pos = origin + IVec3::new(x, y, z);
+                    let block = if pos.y > height {
+                        continue;
+                    } else if pos.y == height {
+                        surface
+                    } else if pos.y > height - self.filler_depth {
+                        filler
+                    } else {
+                        stone
+                    };
+                    chunk.set_block(pos, block);
+                }
+            }
+        }
+        return chunk;
+    }
+}
@@ -0,0 +1,120 @@
+use bevy::app::{App, Plugin, Update};
+use bevy::core_pipeline::core_3d::Camera3d;
+use bevy::input::ButtonInput;
+use bevy::input::mouse::MouseWheel;
+use bevy::log::info;
+use bevy::prelude::{EventReader, GlobalTransform, IntoSystemConfigs, KeyCode, MouseButton, Query, Res, ResMut, Resource, Time, Window, With};
+
+use crate::block::{BlockId, BlockRegistry};
+use crate::physics::{Collider, PhysicsPosition};
+use crate::player_controller::{grab_mouse, Player};
+use crate::voxel_mesher::ClientWorld;
+use crate::world::BlockGetter;
+
+pub struct InteractionPlugin;
+
+impl Plugin for InteractionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BlockInteraction>();
+        // Before grabbing the mouse, so the click that grabs it doesn't also break a block
+        app.add_systems(Update, (select_block, interact_with_blocks.before(grab_mouse)));
+    }
+}
+
+#[derive(Debug, Resource)]
+pub struct BlockInteraction {
+    /// The block placed with right click.
+    pub selected: BlockId,
+    /// How far away blocks can be broken or placed.
+    pub reach: f32,
+    /// Seconds between repeated breaks or placements while a button is held.
+    pub repeat_delay: f32,
+    cooldown: f32,
+}
+
+impl Default for BlockInteraction {
+    fn default() -> Self {
+        Self {
+            selected: BlockId(1),
+            reach: 6.0,
+            repeat_delay: 0.25,
+            cooldown: 0.0,
+        }
+    }
+}
+
+/// Number keys select the block with that id, the mouse wheel steps through every placeable block.
+fn select_block(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    registry: Res<BlockRegistry>,
+    mut interaction: ResMut<BlockInteraction>,
+) {
+    const KEYS: [KeyCode; 9] = [
+        KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4, KeyCode::Digit5,
+        KeyCode::Digit6, KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9,
+    ];
+
+    for (index, key) in KEYS.iter().enumerate() {
+        let block = BlockId(index as u16 + 1);
+        if keyboard_input.just_pressed(*key) && registry.get(block).is_placeable() {
+            interaction.selected = block;
+            info!("Selected {}", registry.get(block).name);
+        }
+    }
+
+    let scrolled: f32 = mouse_wheel.read().map(|wheel| wheel.y).sum();
+    if scrolled != 0.0 {
+        let placeable: Vec<BlockId> = (0..registry.len() as u16).map(BlockId).filter(|block| registry.get(*block).is_placeable()).collect();
+        let Some(current) = placeable.iter().position(|block| *block == interaction.selected) else {
+            return;
+        };
+        let step = if scrolled > 0.0 { placeable.len() - 1 } else { 1 };
+        interaction.selected = placeable[(current + step) % placeable.len()];
+        info!("Selected {}", registry.get(interaction.selected).name);
+    }
+}
+
+fn interact_with_blocks(
+    windows: Query<&Window>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    time: Res<Time>,
+    client_world: Res<ClientWorld>,
+    mut interaction: ResMut<BlockInteraction>,
+    camera: Query<&GlobalTransform, With<Camera3d>>,
+    player: Query<(&PhysicsPosition, &Collider), With<Player>>,
+) {
+    interaction.cooldown -= time.delta_seconds();
+    if windows.single().cursor.visible {
+        return;
+    }
+
+    let breaking = mouse.pressed(MouseButton::Left);
+    let placing = mouse.pressed(MouseButton::Right);
+    let clicked = mouse.just_pressed(MouseButton::Left) || mouse.just_pressed(MouseButton::Right);
+    if !(breaking || placing) || !(clicked || interaction.cooldown <= 0.0) {
+        return;
+    }
+
+    let camera = camera.single();
+    let mut world = client_world.0.write().unwrap();
+    let Some(hit) = world.raycast(camera.translation(), camera.forward(), interaction.reach) else {
+        return;
+    };
+
+    if breaking {
+        world.set_block(hit.pos, BlockId::AIR);
+    } else {
+        // A ray starting inside a block has no face to place against
+        let target = hit.pos + hit.normal;
+        if target == hit.pos {
+            return;
+        }
+        let (position, collider) = player.single();
+        if collider.intersects_block(position.current, target) {
+            return;
+        }
+        world.set_block(target, interaction.selected);
+    }
+    interaction.cooldown = interaction.repeat_delay;
+}
@@ -2,14 +2,15 @@ use std::ops::Deref;
 
 use bevy::core_pipeline::experimental::taa::TemporalAntiAliasBundle;
 use bevy::core_pipeline::fxaa::Fxaa;
-use bevy::pbr::ScreenSpaceAmbientOcclusionBundle;
 use bevy::prelude::*;
 use bevy_atmosphere::prelude::*;
 
 use crate::axis::AxisPlugin;
-use crate::physics::{PhysicsPlugin, Velocity};
+use crate::fluid::FluidPlugin;
+use crate::interaction::InteractionPlugin;
+use crate::physics::{Collider, Contacts, PhysicsPlugin, PhysicsPosition, Velocity};
 use crate::player_controller::{CameraRotation, Player, PlayerControllerPlugin};
-use crate::voxel_mesher::{ClientWorld, schedule, VoxelPlugin};
+use crate::voxel_mesher::{ChunkLoader, ClientWorld, MeshingMode, MeshingSettings, VoxelPlugin, VoxelVertexFormat};
 use crate::world::VoxelWorld;
 
 mod physics;
@@ -19,6 +20,14 @@ mod voxel_renderer;
 mod world;
 mod hud;
 mod axis;
+mod palette;
+mod block;
+mod region;
+mod generator;
+mod biome;
+mod structure;
+mod interaction;
+mod fluid;
 
 fn main() {
     App::new()
@@ -34,6 +43,8 @@ fn main() {
                       // HudPlugin,
                       AxisPlugin,
                       PhysicsPlugin::default(),
+                      InteractionPlugin,
+                      FluidPlugin,
                       // TemporalAntiAliasPlugin
         ))
         .add_systems(Startup, spawn_view_model)
@@ -92,12 +103,18 @@ fn spawn_view_model(
         ..default()
     });
 
+    // Start above the generated terrain and fall onto it
+    let spawn_point = Vec3::new(0.0, 24.0, 0.0);
     commands
         .spawn((
             Player,
-            SpatialBundle::default(),
+            SpatialBundle::from_transform(Transform::from_translation(spawn_point)),
+            PhysicsPosition::new(spawn_point),
             Velocity::default(),
-            CameraRotation::default()
+            CameraRotation::default(),
+            Collider { half_width: 0.3, height: 1.5, step_height: 1.0 },
+            Contacts::default(),
+            ChunkLoader { radius: 4 }
         ))
         .with_children(|parent| {
             parent.spawn((
@@ -115,22 +132,38 @@ fn spawn_view_model(
                 },
                 AtmosphereCamera::default(),
                 Fxaa::default()
-            )).insert(ScreenSpaceAmbientOcclusionBundle::default()).insert(TemporalAntiAliasBundle::default());
+            )).insert(TemporalAntiAliasBundle::default());
         });
 
     // commands.run_system(create_voxel_mesh)
 }
 
-fn spawn_mesh(commands: Commands,
-              keyboard_input: Res<ButtonInput<KeyCode>>,
+fn spawn_mesh(keyboard_input: Res<ButtonInput<KeyCode>>,
               client_world: Res<ClientWorld>,
+              mut settings: ResMut<MeshingSettings>,
               camera_transform: Query<&Transform, With<Player>>) {
-    if keyboard_input.just_pressed(KeyCode::KeyE) {
+    if keyboard_input.just_pressed(KeyCode::KeyG) {
+        settings.mode = match settings.mode {
+            MeshingMode::Naive => MeshingMode::Greedy,
+            MeshingMode::Greedy => MeshingMode::Naive,
+        };
+        info!("Meshing mode set to {:?}", settings.mode);
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyV) {
+        settings.vertex_format = match settings.vertex_format {
+            VoxelVertexFormat::Standard => VoxelVertexFormat::Packed,
+            VoxelVertexFormat::Packed => VoxelVertexFormat::Standard,
+        };
+        info!("Vertex format set to {:?}", settings.vertex_format);
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyG) || keyboard_input.just_pressed(KeyCode::KeyV) {
         let mut world = client_world.0.write().unwrap();
-        world.set_block(camera_transform.single().translation.floor().as_ivec3(), VoxelWorld::STONE);
+        let loaded: Vec<IVec3> = world.get_chunks().map(|(chunk_pos, _)| chunk_pos).collect();
+        for chunk_pos in loaded {
+            world.mark_dirty(chunk_pos);
+        }
     }
-    if keyboard_input.just_pressed(KeyCode::KeyE) || keyboard_input.just_pressed(KeyCode::KeyK) {
-        // FIXME delete old mesh
-        schedule(commands, client_world.0.clone(), (camera_transform.single().translation / Vec3::splat(VoxelWorld::CHUNK_SIZE as f32)).floor().as_ivec3());
+    if keyboard_input.just_pressed(KeyCode::KeyK) {
+        client_world.0.write().unwrap().mark_dirty(VoxelWorld::chunk_pos(camera_transform.single().translation.floor().as_ivec3()));
     }
 }
\ No newline at end of file
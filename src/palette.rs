@@ -0,0 +1,305 @@
+/// A fixed-length array of values that stores uniform contents as a single value and mixed
+/// contents as a palette of the distinct values plus bit-packed indices into it.
+#[derive(Debug, Clone)]
+pub struct PalettedContainer<T> {
+    len: usize,
+    storage: Storage<T>,
+}
+
+#[derive(Debug, Clone)]
+enum Storage<T> {
+    Single(T),
+    Indirect {
+        palette: Vec<T>,
+        indices: BitArray,
+    },
+}
+
+impl<T: Copy + Eq> PalettedContainer<T> {
+    pub fn filled(len: usize, value: T) -> Self {
+        Self {
+            len,
+            storage: Storage::Single(value),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> T {
+        return match &self.storage {
+            Storage::Single(value) => *value,
+            Storage::Indirect { palette, indices } => palette[indices.get(index) as usize],
+        };
+    }
+
+    pub fn set(&mut self, index: usize, value: T) {
+        if let Storage::Single(current) = self.storage {
+            if current == value {
+                return;
+            }
+            self.storage = Storage::indirect(current, self.len);
+        }
+
+        let palette_index = self.palette_index(value);
+        if let Storage::Indirect { indices, .. } = &mut self.storage {
+            indices.set(index, palette_index as u64);
+        }
+    }
+
+    /// Finds or inserts the value in the palette, re-packing the indices if it outgrows them.
+    fn palette_index(&mut self, value: T) -> usize {
+        if let Some(palette_index) = self.palette().iter().position(|entry| *entry == value) {
+            return palette_index;
+        }
+
+        if let Storage::Indirect { palette, indices } = &self.storage {
+            if palette.len() == 1 << indices.bits() {
+                // Reclaim entries that were overwritten before paying for wider indices
+                self.compact();
+            }
+        }
+        if let Storage::Single(current) = self.storage {
+            self.storage = Storage::indirect(current, self.len);
+        }
+
+        let len = self.len;
+        let Storage::Indirect { palette, indices } = &mut self.storage else {
+            unreachable!();
+        };
+        if palette.len() == 1 << indices.bits() {
+            *indices = indices.resized(indices.bits() + 1, len);
+        }
+        palette.push(value);
+        return palette.len() - 1;
+    }
+
+    /// The distinct values that may be present. This can include values that were overwritten
+    /// since the last [`PalettedContainer::compact`].
+    pub fn palette(&self) -> &[T] {
+        return match &self.storage {
+            Storage::Single(value) => std::slice::from_ref(value),
+            Storage::Indirect { palette, .. } => palette,
+        };
+    }
+
+    pub fn contains(&self, value: T) -> bool {
+        return self.palette().contains(&value);
+    }
+
+    /// Drops palette entries that are no longer referenced and shrinks the indices to match,
+    /// collapsing back into a single value if only one remains.
+    pub fn compact(&mut self) {
+        let Storage::Indirect { palette, indices } = &self.storage else {
+            return;
+        };
+
+        let mut used = vec![false; palette.len()];
+        for i in 0..self.len {
+            used[indices.get(i) as usize] = true;
+        }
+        if used.iter().all(|used| *used) && palette.len() > 1 << (indices.bits() - 1) {
+            return;
+        }
+
+        let mut remap = vec![0u64; palette.len()];
+        let mut new_palette = Vec::new();
+        for (i, entry) in palette.iter().enumerate() {
+            if used[i] {
+                remap[i] = new_palette.len() as u64;
+                new_palette.push(*entry);
+            }
+        }
+
+        if new_palette.len() == 1 {
+            self.storage = Storage::Single(new_palette[0]);
+            return;
+        }
+
+        let mut new_indices = BitArray::new(BitArray::bits_for(new_palette.len()), self.len);
+        for i in 0..self.len {
+            new_indices.set(i, remap[indices.get(i) as usize]);
+        }
+        self.storage = Storage::Indirect {
+            palette: new_palette,
+            indices: new_indices,
+        };
+    }
+}
+
+impl<T> Storage<T> {
+    fn indirect(value: T, len: usize) -> Self {
+        Storage::Indirect {
+            palette: vec![value],
+            indices: BitArray::new(1, len),
+        }
+    }
+}
+
+/// Fixed width unsigned integers packed into `u64` words. Entries never straddle two words.
+#[derive(Debug, Clone)]
+struct BitArray {
+    bits: u32,
+    data: Vec<u64>,
+}
+
+impl BitArray {
+    fn new(bits: u32, len: usize) -> Self {
+        let per_word = (64 / bits) as usize;
+        Self {
+            bits,
+            data: vec![0; (len + per_word - 1) / per_word],
+        }
+    }
+
+    fn bits_for(palette_len: usize) -> u32 {
+        return (usize::BITS - (palette_len - 1).leading_zeros()).max(1);
+    }
+
+    fn bits(&self) -> u32 {
+        return self.bits;
+    }
+
+    fn mask(&self) -> u64 {
+        return (1 << self.bits) - 1;
+    }
+
+    fn get(&self, index: usize) -> u64 {
+        let per_word = (64 / self.bits) as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        return (self.data[index / per_word] >> shift) & self.mask();
+    }
+
+    fn set(&mut self, index: usize, value: u64) {
+        let per_word = (64 / self.bits) as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        let mask = self.mask();
+        let word = &mut self.data[index / per_word];
+        *word = (*word & !(mask << shift)) | ((value & mask) << shift);
+    }
+
+    fn resized(&self, bits: u32, len: usize) -> Self {
+        let mut resized = BitArray::new(bits, len);
+        for i in 0..len {
+            resized.set(i, self.get(i));
+        }
+        return resized;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 4096;
+
+    /// Width of the packed indices, or `None` while the container holds a single value.
+    fn index_bits<T>(container: &PalettedContainer<T>) -> Option<u32> {
+        return match &container.storage {
+            Storage::Single(_) => None,
+            Storage::Indirect { indices, .. } => Some(indices.bits()),
+        };
+    }
+
+    #[test]
+    fn filled_container_stays_single_when_set_to_the_same_value() {
+        let mut container = PalettedContainer::filled(LEN, 7u16);
+        container.set(100, 7);
+        assert_eq!(index_bits(&container), None);
+        assert_eq!(container.palette(), &[7]);
+        assert!((0..LEN).all(|i| container.get(i) == 7));
+    }
+
+    #[test]
+    fn indices_widen_as_the_palette_grows() {
+        let mut container = PalettedContainer::filled(LEN, 0u16);
+        for value in 1..=256u16 {
+            container.set(value as usize, value);
+            // The palette now holds every value up to this one
+            let bits = match value {
+                1 => 1,
+                2..=3 => 2,
+                4..=7 => 3,
+                8..=15 => 4,
+                16..=31 => 5,
+                32..=63 => 6,
+                64..=127 => 7,
+                128..=255 => 8,
+                _ => 9,
+            };
+            assert_eq!(index_bits(&container), Some(bits), "after inserting {}", value);
+        }
+        for i in 0..LEN {
+            let expected = if i <= 256 { i as u16 } else { 0 };
+            assert_eq!(container.get(i), expected, "at {}", i);
+        }
+    }
+
+    #[test]
+    fn compact_shrinks_indices_and_collapses_to_a_single_value() {
+        let mut container = PalettedContainer::filled(LEN, 0u16);
+        for value in 1..=16u16 {
+            container.set(value as usize, value);
+        }
+        assert_eq!(index_bits(&container), Some(5));
+
+        for value in 3..=16u16 {
+            container.set(value as usize, 0);
+        }
+        container.compact();
+        assert_eq!(index_bits(&container), Some(2));
+        assert_eq!(container.palette(), &[0, 1, 2]);
+        assert_eq!((container.get(1), container.get(2), container.get(3)), (1, 2, 0));
+
+        container.set(1, 0);
+        container.set(2, 0);
+        container.compact();
+        assert_eq!(index_bits(&container), None);
+        assert_eq!(container.palette(), &[0]);
+    }
+
+    #[test]
+    fn full_palette_reclaims_overwritten_entries_before_widening() {
+        let mut container = PalettedContainer::filled(LEN, 0u16);
+        container.set(0, 1);
+        container.set(0, 0);
+        // The palette still holds 1 and is full at one bit, but 1 is no longer used
+        assert_eq!(container.palette(), &[0, 1]);
+        container.set(5, 2);
+        assert_eq!(index_bits(&container), Some(1));
+        assert_eq!(container.palette(), &[0, 2]);
+        assert_eq!((container.get(0), container.get(5)), (0, 2));
+        assert!(!container.contains(1));
+    }
+
+    #[test]
+    fn bits_for_covers_every_palette_index() {
+        assert_eq!(BitArray::bits_for(1), 1);
+        assert_eq!(BitArray::bits_for(2), 1);
+        assert_eq!(BitArray::bits_for(3), 2);
+        assert_eq!(BitArray::bits_for(4), 2);
+        assert_eq!(BitArray::bits_for(5), 3);
+        assert_eq!(BitArray::bits_for(256), 8);
+        assert_eq!(BitArray::bits_for(257), 9);
+    }
+
+    #[test]
+    fn entries_next_to_word_borders_do_not_overlap() {
+        // 21 three bit entries fit in a word, leaving its top bit unused
+        let mut bits = BitArray::new(3, 64);
+        assert_eq!(bits.data.len(), 4);
+        for i in 0..64 {
+            bits.set(i, (i % 8) as u64);
+        }
+        bits.set(20, 7);
+        bits.set(21, 5);
+        for i in 0..64 {
+            let expected = match i {
+                20 => 7,
+                21 => 5,
+                _ => (i % 8) as u64,
+            };
+            assert_eq!(bits.get(i), expected, "at {}", i);
+        }
+
+        let resized = bits.resized(5, 64);
+        assert!((0..64).all(|i| resized.get(i) == bits.get(i)));
+    }
+}
@@ -1,35 +1,120 @@
-use bevy::app::{App, Plugin, Update};
-use bevy::math::Vec3;
-use bevy::prelude::{Component, Query, Res, Resource, Time, Transform};
+use bevy::app::{App, FixedUpdate, Plugin, Update};
+use bevy::math::{IVec3, Vec3};
+use bevy::prelude::{Component, default, Fixed, Query, Res, Resource, Time, Transform};
 
 use crate::voxel_mesher::ClientWorld;
-use crate::world::BlockGetter;
+use crate::world::{BlockGetter, VoxelWorld};
+
 #[derive(Debug)]
 pub struct PhysicsPlugin {
     pub gravity: f32,
+    /// How quickly horizontal velocity decays, per second.
+    pub drag: f32,
+    /// Upward force on a fully submerged body, relative to gravity.
+    pub buoyancy: f32,
+    /// How quickly velocity decays in a fluid, per second, on top of the usual drag.
+    pub fluid_drag: f32,
 }
 
+/// In units per second.
 #[derive(Default, Debug, Component)]
 pub struct Velocity(pub Vec3);
 
+/// Where the body is in the simulation, which runs in fixed steps. Its `Transform` is placed between the last two
+/// steps, so movement looks smooth at any frame rate.
+#[derive(Debug, Component)]
+pub struct PhysicsPosition {
+    pub current: Vec3,
+    pub previous: Vec3,
+    /// How far the body was lifted by stepping onto a ledge that the `Transform` hasn't caught up with yet.
+    pub step_offset: f32,
+}
+
+impl PhysicsPosition {
+    pub fn new(position: Vec3) -> Self {
+        Self {
+            current: position,
+            previous: position,
+            step_offset: 0.0,
+        }
+    }
+}
+
+/// An upright box around the entity, standing on its translation.
+#[derive(Debug, Copy, Clone, Component)]
+pub struct Collider {
+    pub half_width: f32,
+    pub height: f32,
+    /// How high a ledge the body walks onto without jumping.
+    pub step_height: f32,
+}
+
+impl Collider {
+    /// The minimum and maximum corner of the box when the entity is at `translation`.
+    pub fn aabb(&self, translation: Vec3) -> (Vec3, Vec3) {
+        let half_extents = Vec3::new(self.half_width, 0.0, self.half_width);
+        return (translation - half_extents, translation + half_extents + Vec3::Y * self.height);
+    }
+
+    pub fn intersects_block(&self, translation: Vec3, block: IVec3) -> bool {
+        let (min, max) = self.aabb(translation);
+        let (block_min, block_max) = (block.as_vec3(), block.as_vec3() + Vec3::ONE);
+        return min.cmplt(block_max).all() && max.cmpgt(block_min).all();
+    }
+}
+
+/// What a collider touched during its last move.
+#[derive(Debug, Component)]
+pub struct Contacts {
+    pub grounded: bool,
+    pub touching_wall: bool,
+    pub touching_ceiling: bool,
+    /// How much of the body is inside a fluid, from 0 to 1.
+    pub submerged: f32,
+    /// The friction of the block the body stands on, which scales how quickly it slows down. 1 while airborne.
+    pub friction: f32,
+}
+
+impl Default for Contacts {
+    fn default() -> Self {
+        Self {
+            grounded: false,
+            touching_wall: false,
+            touching_ceiling: false,
+            submerged: 0.0,
+            friction: 1.0,
+        }
+    }
+}
+
 #[derive(Debug, Resource)]
 struct PhysicsSettings {
     gravity: f32,
+    drag: f32,
+    buoyancy: f32,
+    fluid_drag: f32,
 }
 
 impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(PhysicsSettings {
-            gravity: self.gravity
+            gravity: self.gravity,
+            drag: self.drag,
+            buoyancy: self.buoyancy,
+            fluid_drag: self.fluid_drag,
         });
-        app.add_systems(Update, apply_velocity);
+        app.add_systems(FixedUpdate, apply_velocity);
+        app.add_systems(Update, interpolate_transforms);
     }
 }
 
 impl Default for PhysicsPlugin {
     fn default() -> Self {
         Self {
-            gravity: 9.81
+            gravity: 9.81,
+            drag: 10.0,
+            buoyancy: 1.3,
+            fluid_drag: 3.0,
         }
     }
 }
@@ -38,18 +123,260 @@ fn apply_velocity(
     settings: Res<PhysicsSettings>,
     world: Res<ClientWorld>,
     time: Res<Time>,
-    mut transforms: Query<(&mut Transform, &mut Velocity)>) {
-    let delta = time.delta().as_millis() as f32 / 1_000.0;
+    mut bodies: Query<(&mut PhysicsPosition, &mut Velocity, &Collider, &mut Contacts)>) {
+    // In units per second
+    const MIN_BOUNCE_SPEED: f32 = 1.0;
+
+    let delta = time.delta_seconds();
+
+    let world = world.0.read().unwrap();
+    for (mut position, mut vel, collider, mut contacts) in bodies.iter_mut() {
+        position.previous = position.current;
+
+        // Hold still until the terrain around the body exists, rather than falling through it
+        if !world.is_loaded(VoxelWorld::chunk_pos(position.current.floor().as_ivec3())) {
+            continue;
+        }
+
+        // The block the body stood on after the last step decides how it slides and how fast it moves
+        let (friction, speed) = if contacts.grounded {
+            let ground = world.get_properties(ground_block(&*world, collider, position.current));
+            (ground.friction, ground.speed)
+        } else {
+            (1.0, 1.0)
+        };
+
+        let drag = (-settings.drag * friction * delta).exp();
+        vel.0.x *= drag;
+        vel.0.z *= drag;
+        vel.0.y -= settings.gravity * delta;
+
+        // Floating up while mostly submerged, so a body comes to rest with its top part out of the fluid
+        let submerged = submersion(&*world, collider, position.current);
+        vel.0.y += settings.gravity * settings.buoyancy * submerged * delta;
+        vel.0 *= (-settings.fluid_drag * submerged * delta).exp();
+
+        // Resolve one axis at a time, vertical first, so sliding along walls and floors keeps the other components
+        *contacts = Contacts {
+            submerged,
+            friction,
+            ..default()
+        };
+        for axis in [1, 0, 2] {
+            let distance = vel.0[axis] * delta * if axis == 1 { 1.0 } else { speed };
+            let moved = sweep_axis(&world, collider, position.current, axis, distance);
+            if axis != 1 && moved != distance && contacts.grounded {
+                if let Some(stepped) = step_up(&world, collider, position.current, axis, distance, moved) {
+                    // The lift is eased in by the step offset alone, so the interpolation must not also rise over it
+                    let lift = stepped.y - position.current.y;
+                    position.step_offset += lift;
+                    position.previous.y += lift;
+                    position.current = stepped;
+                    continue;
+                }
+            }
+            position.current[axis] += moved;
+            if moved == distance {
+                continue;
+            }
+
+            match axis {
+                1 if vel.0.y < 0.0 => {
+                    contacts.grounded = true;
+                    vel.0.y *= -world.get_properties(ground_block(&*world, collider, position.current)).restitution;
+                    // Settle instead of bouncing forever in ever smaller hops
+                    if vel.0.y < MIN_BOUNCE_SPEED {
+                        vel.0.y = 0.0;
+                    }
+                }
+                1 => {
+                    contacts.touching_ceiling = true;
+                    vel.0.y = 0.0;
+                }
+                _ => {
+                    contacts.touching_wall = true;
+                    vel.0[axis] = 0.0;
+                }
+            }
+        }
+    }
+}
+
+/// How much of the collider at `position` is inside a fluid, sampled at a few heights along its center.
+fn submersion(world: &dyn BlockGetter, collider: &Collider, position: Vec3) -> f32 {
+    const SAMPLES: usize = 4;
+
+    let inside = (0..SAMPLES)
+        .map(|i| position + Vec3::Y * collider.height * (i as f32 + 0.5) / SAMPLES as f32)
+        .filter(|sample| world.get_properties(sample.floor().as_ivec3()).fluid.is_some())
+        .count();
+    return inside as f32 / SAMPLES as f32;
+}
 
-    for (mut transform, mut vel) in transforms.iter_mut() {
-        vel.0.x *= 0.6;
-        vel.0.z *= 0.6;
-        vel.0.y -= settings.gravity * delta * delta;
-        transform.translation += vel.0;
+/// The collidable block below the collider at `position` that the most of its footprint rests on, or the one below its
+/// center if it isn't standing on any.
+fn ground_block(world: &dyn BlockGetter, collider: &Collider, position: Vec3) -> IVec3 {
+    // Keeps a footprint that exactly ends on a block border from counting the block beyond it
+    const EPSILON: f32 = 1e-4;
 
-        if transform.translation.y < 0.0 {
-            transform.translation.y = 0.0;
-            vel.0 = Vec3::default();
+    let (min, max) = collider.aabb(position);
+    let y = (position.y - 0.01).floor() as i32;
+    let mut ground = (position - Vec3::Y * 0.01).floor().as_ivec3();
+    let mut largest = 0.0;
+    for x in (min.x + EPSILON).floor() as i32..=(max.x - EPSILON).floor() as i32 {
+        for z in (min.z + EPSILON).floor() as i32..=(max.z - EPSILON).floor() as i32 {
+            let block = IVec3::new(x, y, z);
+            if !world.is_collidable(block) {
+                continue;
+            }
+            let overlap = (max.x.min((x + 1) as f32) - min.x.max(x as f32)) * (max.z.min((z + 1) as f32) - min.z.max(z as f32));
+            if overlap > largest {
+                largest = overlap;
+                ground = block;
+            }
         }
     }
-}
\ No newline at end of file
+    return ground;
+}
+
+fn interpolate_transforms(fixed_time: Res<Time<Fixed>>, time: Res<Time>, mut bodies: Query<(&mut Transform, &mut PhysicsPosition)>) {
+    // How quickly a step up is eased in, per second
+    const STEP_SMOOTHING: f32 = 15.0;
+
+    let alpha = fixed_time.overstep_fraction();
+    for (mut transform, mut position) in bodies.iter_mut() {
+        position.step_offset *= (-STEP_SMOOTHING * time.delta_seconds()).exp();
+        transform.translation = position.previous.lerp(position.current, alpha) - Vec3::Y * position.step_offset;
+    }
+}
+
+/// Tries the blocked horizontal move again with the body raised by up to its step height, then lowers it back onto
+/// whatever it ended up above. Returns the new position if that got further than the blocked move.
+fn step_up(world: &VoxelWorld, collider: &Collider, start: Vec3, axis: usize, distance: f32, moved: f32) -> Option<Vec3> {
+    let rise = sweep_axis(world, collider, start, 1, collider.step_height);
+    let mut position = start + Vec3::Y * rise;
+    let stepped = sweep_axis(world, collider, position, axis, distance);
+    if stepped.abs() <= moved.abs() {
+        return None;
+    }
+    position[axis] += stepped;
+    position.y += sweep_axis(world, collider, position, 1, -rise);
+    return Some(position);
+}
+
+/// How far the collider at `translation` can move along `axis`, up to `distance`, before it hits a collidable block.
+/// Chunks that aren't loaded count as solid, so nothing moves into terrain that doesn't exist yet.
+fn sweep_axis(world: &VoxelWorld, collider: &Collider, translation: Vec3, axis: usize, distance: f32) -> f32 {
+    // Keeps boxes that are exactly touching a block from counting as overlapping it
+    const EPSILON: f32 = 1e-4;
+
+    if distance == 0.0 {
+        return 0.0;
+    }
+
+    let (min, max) = collider.aabb(translation);
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    let blocked = |layer: i32| {
+        for i in (min[a] + EPSILON).floor() as i32..=(max[a] - EPSILON).floor() as i32 {
+            for j in (min[b] + EPSILON).floor() as i32..=(max[b] - EPSILON).floor() as i32 {
+                let mut pos = IVec3::ZERO;
+                pos[axis] = layer;
+                pos[a] = i;
+                pos[b] = j;
+                if world.is_collidable(pos) || !world.is_loaded(VoxelWorld::chunk_pos(pos)) {
+                    return true;
+                }
+            }
+        }
+        return false;
+    };
+
+    // Check each layer of blocks the leading face would enter, nearest first
+    if distance > 0.0 {
+        let leading = max[axis];
+        for layer in (leading - EPSILON).ceil() as i32..=(leading + distance - EPSILON).floor() as i32 {
+            if blocked(layer) {
+                return (layer as f32 - leading).min(distance);
+            }
+        }
+    } else {
+        let leading = min[axis];
+        for layer in ((leading + distance + EPSILON).floor() as i32..(leading + EPSILON).floor() as i32).rev() {
+            if blocked(layer) {
+                return (layer as f32 + 1.0 - leading).max(distance);
+            }
+        }
+    }
+    return distance;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BlockId, BlockRegistry};
+    use crate::world::RenderChunk;
+
+    const COLLIDER: Collider = Collider { half_width: 0.25, height: 1.5, step_height: 1.0 };
+
+    /// A world with the given chunks loaded and empty, and stone at the given positions.
+    fn world_with(chunks: &[IVec3], stone: &[IVec3]) -> VoxelWorld {
+        let mut world = VoxelWorld::create(BlockRegistry::builtin());
+        for chunk_pos in chunks {
+            world.load_chunk(*chunk_pos, RenderChunk::create_solid(BlockId::AIR));
+        }
+        for pos in stone {
+            world.set_block(*pos, BlockId(1));
+        }
+        return world;
+    }
+
+    fn around_origin(stone: &[IVec3]) -> VoxelWorld {
+        let mut chunks = Vec::new();
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    chunks.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+        return world_with(&chunks, stone);
+    }
+
+    #[test]
+    fn bodies_resting_on_the_floor_do_not_sink_into_it() {
+        let world = around_origin(&[IVec3::new(0, -1, 0)]);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.0, 0.5), 1, -1.0), 0.0);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.25, 0.5), 1, -1.0), -0.25);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.25, 0.5), 1, -0.125), -0.125);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.0, 0.5), 1, 0.5), 0.5);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.0, 0.5), 1, 0.0), 0.0);
+    }
+
+    #[test]
+    fn walls_stop_bodies_at_their_face() {
+        let world = around_origin(&[IVec3::new(2, 0, 0), IVec3::new(-2, 1, 0)]);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.0, 0.5), 0, 2.0), 1.25);
+        // Already touching the wall, the body can only move away from it
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(1.75, 0.0, 0.5), 0, 1.0), 0.0);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(1.75, 0.0, 0.5), 0, -1.0), -1.0);
+        // The block at head height stops the body too
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.0, 0.5), 0, -3.0), -1.25);
+    }
+
+    #[test]
+    fn blocks_only_touching_the_side_of_a_body_do_not_block_it() {
+        let world = around_origin(&[IVec3::new(2, 0, 0), IVec3::new(0, 2, 0)]);
+        // Sliding along the wall, and standing right below a block
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(1.75, 0.0, -1.5), 2, 3.0), 3.0);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.5, 0.5), 2, 1.0), 1.0);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 0.5, 0.5), 1, 1.0), 0.0);
+    }
+
+    #[test]
+    fn unloaded_chunks_are_solid() {
+        let world = world_with(&[IVec3::ZERO], &[]);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(0.5, 1.0, 0.5), 0, -2.0), -0.25);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(8.5, 14.0, 8.5), 1, 3.0), 0.5);
+        assert_eq!(sweep_axis(&world, &COLLIDER, Vec3::new(8.5, 14.0, 8.5), 1, -3.0), -3.0);
+    }
+}
@@ -7,7 +7,7 @@ use bevy::math::{Quat, Vec3};
 use bevy::prelude::{Children, Component, EventReader, KeyCode, MouseButton, Query, Res, Time, Transform, Window, With};
 use bevy::window::CursorGrabMode;
 
-use crate::physics::Velocity;
+use crate::physics::{Contacts, Velocity};
 
 #[derive(Debug, Component)]
 pub struct Player;
@@ -57,7 +57,7 @@ fn rotate_camera(
     }
 }
 
-fn grab_mouse(
+pub fn grab_mouse(
     mut windows: Query<&mut Window>,
     mouse: Res<ButtonInput<MouseButton>>,
     key: Res<ButtonInput<KeyCode>>,
@@ -78,7 +78,7 @@ fn grab_mouse(
 fn move_player(
     keyboard_input: Res<ButtonInput<KeyCode>>,
     time: Res<Time>,
-    mut camera_transform: Query<(&CameraRotation, &mut Velocity), With<Player>>,
+    mut camera_transform: Query<(&CameraRotation, &mut Velocity, &Contacts), With<Player>>,
 ) {
     let mut dx: f32 = 0.0;
     let mut dz: f32 = 0.0;
@@ -96,12 +96,22 @@ fn move_player(
         dx += 1.0;
     }
 
-    let (rotation, mut velocity) = camera_transform.single_mut();
-    let delta = time.delta().as_millis() as f32 / 1_000.0;
-    if keyboard_input.just_pressed(KeyCode::Space) {
-        velocity.0.y = 4.0 * delta;
+    // In units per second, walking speed levels off where the acceleration matches the physics drag. Both scale with
+    // the friction of the ground, so slippery ground is slow to speed up and slow down but keeps the same top speed.
+    const WALK_ACCELERATION: f32 = 45.0;
+    const JUMP_SPEED: f32 = 5.5;
+    const SWIM_ACCELERATION: f32 = 20.0;
+
+    let (rotation, mut velocity, contacts) = camera_transform.single_mut();
+    let delta = time.delta_seconds();
+    if keyboard_input.just_pressed(KeyCode::Space) && contacts.grounded {
+        velocity.0.y = JUMP_SPEED;
+    }
+    // Holding jump swims upwards
+    if keyboard_input.pressed(KeyCode::Space) && contacts.submerged > 0.0 {
+        velocity.0.y += SWIM_ACCELERATION * delta;
     }
 
     let quat = Quat::from_rotation_y(rotation.yaw);
-    velocity.0 += quat.mul_vec3(Vec3::new(dx, 0.0, dz).normalize_or_zero() * 4.0 * delta);
+    velocity.0 += quat.mul_vec3(Vec3::new(dx, 0.0, dz).normalize_or_zero() * WALK_ACCELERATION * contacts.friction * delta);
 }
\ No newline at end of file
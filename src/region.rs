@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::math::IVec3;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::block::BlockId;
+use crate::world::RenderChunk;
+
+// Region file layout, all integers little endian:
+//
+// magic     4 bytes  "VXRG"
+// version   u32
+// entries   REGION_VOLUME * (offset: u32, length: u32, compression: u8), in chunk index order.
+//           A length of 0 means the chunk is not present.
+// payloads  chunk data at the offsets given in the header
+//
+// Chunk payload, before compression:
+//
+// palette_len  u16
+// palette      palette_len * u16 block ids
+// bits         u8, 0 when the chunk is a single block
+// indices      ceil(VOLUME / (64 / bits)) * u64, bit-packed palette indices that never straddle a word
+const MAGIC: &[u8; 4] = b"VXRG";
+const VERSION: u32 = 1;
+const ENTRY_SIZE: usize = 9;
+const HEADER_SIZE: usize = 8 + REGION_VOLUME * ENTRY_SIZE;
+
+/// Number of chunks along each axis of a region file.
+pub const REGION_SIZE: i32 = 16;
+const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+/// Payloads smaller than this are stored uncompressed since deflate would only grow them.
+const COMPRESSION_THRESHOLD: usize = 64;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+enum ChunkCompression {
+    None = 0,
+    Zlib = 1,
+}
+
+impl TryFrom<u8> for ChunkCompression {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        return match value {
+            0 => Ok(ChunkCompression::None),
+            1 => Ok(ChunkCompression::Zlib),
+            _ => Err(io::Error::new(ErrorKind::InvalidData, format!("Unknown chunk compression {}", value))),
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct RegionEntry {
+    offset: u32,
+    length: u32,
+    compression: ChunkCompression,
+}
+
+/// A directory of region files, each holding a cube of [`REGION_SIZE`] chunks.
+#[derive(Debug, Clone)]
+pub struct RegionStorage {
+    dir: PathBuf,
+}
+
+impl RegionStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+        }
+    }
+
+    /// Whether anything has been saved here yet.
+    pub fn exists(&self) -> bool {
+        return self.dir.is_dir();
+    }
+
+    fn region_pos(chunk_pos: IVec3) -> IVec3 {
+        return chunk_pos.div_euclid(IVec3::splat(REGION_SIZE));
+    }
+
+    fn chunk_index(chunk_pos: IVec3) -> usize {
+        let local = chunk_pos.rem_euclid(IVec3::splat(REGION_SIZE));
+        return (local.x + (local.y + local.z * REGION_SIZE) * REGION_SIZE) as usize;
+    }
+
+    fn region_path(&self, region_pos: IVec3) -> PathBuf {
+        return self.dir.join(format!("r.{}.{}.{}.region", region_pos.x, region_pos.y, region_pos.z));
+    }
+
+    pub fn read_chunk(&self, chunk_pos: IVec3) -> io::Result<Option<RenderChunk>> {
+        let mut file = match File::open(self.region_path(RegionStorage::region_pos(chunk_pos))) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let entries = read_header(&mut file)?;
+        let entry = entries[RegionStorage::chunk_index(chunk_pos)];
+        if entry.length == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(decode_chunk(&read_payload(&mut file, entry)?)?));
+    }
+
+    /// Writes the chunks into their region files, keeping any other chunks already stored in them.
+    pub fn write_chunks(&self, chunks: &[(IVec3, RenderChunk)]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut regions: HashMap<IVec3, Vec<&(IVec3, RenderChunk)>> = HashMap::new();
+        for chunk in chunks {
+            regions.entry(RegionStorage::region_pos(chunk.0)).or_default().push(chunk);
+        }
+
+        for (region_pos, chunks) in regions {
+            let path = self.region_path(region_pos);
+            let mut payloads: Vec<Option<(ChunkCompression, Vec<u8>)>> = vec![None; REGION_VOLUME];
+            if let Ok(mut file) = File::open(&path) {
+                let entries = read_header(&mut file)?;
+                for (index, entry) in entries.iter().enumerate() {
+                    if entry.length > 0 {
+                        payloads[index] = Some((entry.compression, read_raw_payload(&mut file, *entry)?));
+                    }
+                }
+            }
+            for (chunk_pos, chunk) in chunks {
+                payloads[RegionStorage::chunk_index(*chunk_pos)] = Some(encode_chunk(chunk)?);
+            }
+
+            // Write next to the region and swap it in so a crash never leaves a half written file
+            let temp_path = path.with_extension("region.tmp");
+            write_region(&mut File::create(&temp_path)?, &payloads)?;
+            fs::rename(&temp_path, &path)?;
+        }
+        return Ok(());
+    }
+
+    pub fn read_all(&self) -> io::Result<Vec<(IVec3, RenderChunk)>> {
+        let mut chunks = Vec::new();
+        if !self.exists() {
+            return Ok(chunks);
+        }
+
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            let Some(region_pos) = parse_region_name(&path) else {
+                continue;
+            };
+            let mut file = File::open(&path)?;
+            let entries = read_header(&mut file)?;
+            for (index, entry) in entries.iter().enumerate() {
+                if entry.length == 0 {
+                    continue;
+                }
+                let index = index as i32;
+                let local = IVec3::new(index % REGION_SIZE, (index / REGION_SIZE) % REGION_SIZE, index / (REGION_SIZE * REGION_SIZE));
+                chunks.push((region_pos * REGION_SIZE + local, decode_chunk(&read_payload(&mut file, *entry)?)?));
+            }
+        }
+        return Ok(chunks);
+    }
+}
+
+fn parse_region_name(path: &Path) -> Option<IVec3> {
+    let name = path.file_name()?.to_str()?.strip_prefix("r.")?.strip_suffix(".region")?;
+    let mut parts = name.split('.').map(|part| part.parse::<i32>());
+    let region_pos = IVec3::new(parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+    return if parts.next().is_none() { Some(region_pos) } else { None };
+}
+
+fn read_header(file: &mut File) -> io::Result<Vec<RegionEntry>> {
+    let mut header = vec![0u8; HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a region file"));
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Unsupported region version {}", version)));
+    }
+
+    let mut entries = Vec::with_capacity(REGION_VOLUME);
+    for entry in header[8..].chunks_exact(ENTRY_SIZE) {
+        entries.push(RegionEntry {
+            offset: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+            length: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+            compression: ChunkCompression::try_from(entry[8])?,
+        });
+    }
+    return Ok(entries);
+}
+
+fn read_raw_payload(file: &mut File, entry: RegionEntry) -> io::Result<Vec<u8>> {
+    let mut payload = vec![0u8; entry.length as usize];
+    file.seek(SeekFrom::Start(entry.offset as u64))?;
+    file.read_exact(&mut payload)?;
+    return Ok(payload);
+}
+
+fn read_payload(file: &mut File, entry: RegionEntry) -> io::Result<Vec<u8>> {
+    let payload = read_raw_payload(file, entry)?;
+    return match entry.compression {
+        ChunkCompression::None => Ok(payload),
+        ChunkCompression::Zlib => {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(payload.as_slice()).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+    };
+}
+
+fn write_region(file: &mut File, payloads: &[Option<(ChunkCompression, Vec<u8>)>]) -> io::Result<()> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mut offset = HEADER_SIZE as u32;
+    for payload in payloads {
+        let (length, compression) = match payload {
+            Some((compression, data)) => (data.len() as u32, *compression),
+            None => (0, ChunkCompression::None),
+        };
+        header.extend_from_slice(&(if length > 0 { offset } else { 0 }).to_le_bytes());
+        header.extend_from_slice(&length.to_le_bytes());
+        header.push(compression as u8);
+        offset += length;
+    }
+
+    file.write_all(&header)?;
+    for (_, data) in payloads.iter().flatten() {
+        file.write_all(data)?;
+    }
+    return file.sync_all();
+}
+
+fn encode_chunk(chunk: &RenderChunk) -> io::Result<(ChunkCompression, Vec<u8>)> {
+    let mut chunk = chunk.clone();
+    chunk.compact();
+    let palette = chunk.palette();
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&(palette.len() as u16).to_le_bytes());
+    for block in palette {
+        data.extend_from_slice(&block.0.to_le_bytes());
+    }
+
+    if palette.len() == 1 {
+        data.push(0);
+    } else {
+        let bits = usize::BITS - (palette.len() - 1).leading_zeros();
+        let per_word = (64 / bits) as usize;
+        let palette_indices: HashMap<BlockId, u64> = palette.iter().enumerate().map(|(i, block)| (*block, i as u64)).collect();
+        data.push(bits as u8);
+        let mut word = 0u64;
+        for i in 0..RenderChunk::VOLUME {
+            word |= palette_indices[&chunk.get_index(i)] << ((i % per_word) as u32 * bits);
+            if i % per_word == per_word - 1 || i == RenderChunk::VOLUME - 1 {
+                data.extend_from_slice(&word.to_le_bytes());
+                word = 0;
+            }
+        }
+    }
+
+    if data.len() < COMPRESSION_THRESHOLD {
+        return Ok((ChunkCompression::None, data));
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data)?;
+    return Ok((ChunkCompression::Zlib, encoder.finish()?));
+}
+
+fn decode_chunk(data: &[u8]) -> io::Result<RenderChunk> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "Truncated chunk payload");
+    let read_u16 = |offset: usize| -> io::Result<u16> {
+        return Ok(u16::from_le_bytes(data.get(offset..offset + 2).ok_or_else(invalid)?.try_into().unwrap()));
+    };
+
+    let palette_len = read_u16(0)? as usize;
+    let mut palette = Vec::with_capacity(palette_len);
+    for i in 0..palette_len {
+        palette.push(BlockId(read_u16(2 + i * 2)?));
+    }
+    if palette.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut offset = 2 + palette_len * 2;
+    let bits = *data.get(offset).ok_or_else(invalid)? as u32;
+    offset += 1;
+    let mut chunk = RenderChunk::create_solid(palette[0]);
+    if bits == 0 {
+        return Ok(chunk);
+    }
+    if bits > 16 {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid index width {}", bits)));
+    }
+
+    let per_word = (64 / bits) as usize;
+    let mask = (1u64 << bits) - 1;
+    for i in 0..RenderChunk::VOLUME {
+        let word_offset = offset + (i / per_word) * 8;
+        let word = u64::from_le_bytes(data.get(word_offset..word_offset + 8).ok_or_else(invalid)?.try_into().unwrap());
+        let palette_index = ((word >> ((i % per_word) as u32 * bits)) & mask) as usize;
+        chunk.set_index(i, *palette.get(palette_index).ok_or_else(invalid)?);
+    }
+    return Ok(chunk);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("voxel-region-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            return Self(path);
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn assert_same_blocks(a: &RenderChunk, b: &RenderChunk) {
+        for i in 0..RenderChunk::VOLUME {
+            assert_eq!(a.get_index(i), b.get_index(i), "at index {}", i);
+        }
+    }
+
+    /// A chunk using `kinds` different blocks in a repeating pattern.
+    fn mixed_chunk(kinds: u16) -> RenderChunk {
+        let mut chunk = RenderChunk::create_solid(BlockId(1));
+        for i in 0..RenderChunk::VOLUME {
+            chunk.set_index(i, BlockId(1 + (i * 7 % kinds as usize) as u16));
+        }
+        return chunk;
+    }
+
+    #[test]
+    fn single_block_chunk_round_trips_uncompressed() {
+        let chunk = RenderChunk::create_solid(BlockId(3));
+        let (compression, data) = encode_chunk(&chunk).unwrap();
+        assert_eq!(compression, ChunkCompression::None);
+        assert_eq!(data, [1, 0, 3, 0, 0]);
+        assert_same_blocks(&decode_chunk(&data).unwrap(), &chunk);
+    }
+
+    #[test]
+    fn mixed_chunks_round_trip_at_every_index_width() {
+        // Five blocks need 3 bit indices, which leave the top bit of every word unused
+        for kinds in [2, 5, 16, 300] {
+            let chunk = mixed_chunk(kinds);
+            let (compression, data) = encode_chunk(&chunk).unwrap();
+            assert_eq!(compression, ChunkCompression::Zlib);
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(data.as_slice()).read_to_end(&mut decompressed).unwrap();
+            assert_same_blocks(&decode_chunk(&decompressed).unwrap(), &chunk);
+        }
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        let (_, data) = encode_chunk(&mixed_chunk(5)).unwrap();
+        let mut decompressed = Vec::new();
+        ZlibDecoder::new(data.as_slice()).read_to_end(&mut decompressed).unwrap();
+        for len in [0, 1, 2 + 5 * 2, decompressed.len() - 1] {
+            assert_eq!(decode_chunk(&decompressed[..len]).err().map(|error| error.kind()), Some(ErrorKind::InvalidData), "at length {}", len);
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip_through_region_files() {
+        let dir = TempDir::new("round-trip");
+        let storage = RegionStorage::new(&dir.0);
+        let chunks = vec![
+            (IVec3::new(0, 0, 0), mixed_chunk(5)),
+            (IVec3::new(15, 15, 15), RenderChunk::create_solid(BlockId(2))),
+            (IVec3::new(-1, -17, 3), mixed_chunk(40)),
+        ];
+        storage.write_chunks(&chunks).unwrap();
+
+        for (chunk_pos, chunk) in &chunks {
+            assert_same_blocks(&storage.read_chunk(*chunk_pos).unwrap().unwrap(), chunk);
+        }
+        // Missing from a region that exists, and from one that doesn't
+        assert!(storage.read_chunk(IVec3::new(1, 0, 0)).unwrap().is_none());
+        assert!(storage.read_chunk(IVec3::new(100, 0, 0)).unwrap().is_none());
+
+        let mut all = storage.read_all().unwrap();
+        all.sort_by_key(|(chunk_pos, _)| chunk_pos.to_array());
+        assert_eq!(all.iter().map(|(chunk_pos, _)| *chunk_pos).collect::<Vec<_>>(), [IVec3::new(-1, -17, 3), IVec3::new(0, 0, 0), IVec3::new(15, 15, 15)]);
+    }
+
+    #[test]
+    fn writing_keeps_the_other_chunks_of_a_region_and_leaves_no_temp_file() {
+        let dir = TempDir::new("rewrite");
+        let storage = RegionStorage::new(&dir.0);
+        storage.write_chunks(&[(IVec3::new(1, 2, 3), mixed_chunk(5)), (IVec3::new(4, 5, 6), mixed_chunk(3))]).unwrap();
+        storage.write_chunks(&[(IVec3::new(1, 2, 3), RenderChunk::create_solid(BlockId(9)))]).unwrap();
+
+        assert_same_blocks(&storage.read_chunk(IVec3::new(1, 2, 3)).unwrap().unwrap(), &RenderChunk::create_solid(BlockId(9)));
+        assert_same_blocks(&storage.read_chunk(IVec3::new(4, 5, 6)).unwrap().unwrap(), &mixed_chunk(3));
+        let names: Vec<String> = fs::read_dir(&dir.0).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        assert_eq!(names, ["r.0.0.0.region"]);
+    }
+
+    #[test]
+    fn files_that_are_not_regions_are_rejected() {
+        let dir = TempDir::new("invalid");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join("r.0.0.0.region"), vec![0u8; HEADER_SIZE]).unwrap();
+        let error = RegionStorage::new(&dir.0).read_chunk(IVec3::ZERO).err().expect("the header should be rejected");
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn region_names_parse_back_to_their_position() {
+        let storage = RegionStorage::new("regions");
+        let path = storage.region_path(IVec3::new(-2, 0, 31));
+        assert_eq!(parse_region_name(&path), Some(IVec3::new(-2, 0, 31)));
+        assert_eq!(parse_region_name(Path::new("r.1.2.region")), None);
+        assert_eq!(parse_region_name(Path::new("r.1.2.3.region.tmp")), None);
+        assert_eq!(RegionStorage::region_pos(IVec3::new(-1, 16, 15)), IVec3::new(-1, 1, 0));
+        assert_eq!(RegionStorage::chunk_index(IVec3::new(-1, 0, 0)), 15);
+    }
+}
@@ -0,0 +1,115 @@
+use bevy::math::IVec3;
+
+/// A multi-block structure placed by world generation, as block names at offsets from the block it stands on.
+#[derive(Debug, Clone)]
+pub struct StructureTemplate {
+    pub name: &'static str,
+    pub blocks: Vec<(IVec3, &'static str)>,
+}
+
+impl StructureTemplate {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn with_block(mut self, offset: IVec3, block: &'static str) -> Self {
+        self.blocks.push((offset, block));
+        return self;
+    }
+
+    /// Fills the box between both corners, inclusive.
+    pub fn with_box(mut self, min: IVec3, max: IVec3, block: &'static str) -> Self {
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    self.blocks.push((IVec3::new(x, y, z), block));
+                }
+            }
+        }
+        return self;
+    }
+
+    /// The smallest and largest offset of any block.
+    pub fn bounds(&self) -> (IVec3, IVec3) {
+        return self.blocks.iter().fold((IVec3::MAX, IVec3::MIN), |(min, max), (offset, _)| (min.min(*offset), max.max(*offset)));
+    }
+
+    /// Every template that biomes can refer to by name.
+    pub fn all() -> Vec<StructureTemplate> {
+        return vec![
+            StructureTemplate::oak_tree(),
+            StructureTemplate::spruce_tree(),
+            StructureTemplate::boulder(),
+            StructureTemplate::ruin(),
+        ];
+    }
+
+    pub fn oak_tree() -> Self {
+        let mut tree = StructureTemplate::new("oak_tree").with_box(IVec3::new(0, 1, 0), IVec3::new(0, 5, 0), "log");
+        for y in 3..=6 {
+            let radius: i32 = if y < 5 { 2 } else { 1 };
+            for x in -radius..=radius {
+                for z in -radius..=radius {
+                    // Leave out the corners so the crown looks round
+                    if x.abs() == radius && z.abs() == radius && (radius == 2 || y == 6) {
+                        continue;
+                    }
+                    tree = tree.with_block(IVec3::new(x, y, z), "leaves");
+                }
+            }
+        }
+        return tree;
+    }
+
+    pub fn spruce_tree() -> Self {
+        let mut tree = StructureTemplate::new("spruce_tree")
+            .with_box(IVec3::new(0, 1, 0), IVec3::new(0, 7, 0), "log")
+            .with_block(IVec3::new(0, 8, 0), "leaves")
+            .with_block(IVec3::new(0, 9, 0), "leaves");
+        for (y, radius) in [(3, 2), (4, 1), (5, 2), (6, 1), (7, 1), (8, 1i32)] {
+            for x in -radius..=radius {
+                for z in -radius..=radius {
+                    if (x, z) != (0, 0) && x.abs() + z.abs() <= radius + 1 - y % 2 {
+                        tree = tree.with_block(IVec3::new(x, y, z), "leaves");
+                    }
+                }
+            }
+        }
+        return tree;
+    }
+
+    pub fn boulder() -> Self {
+        let mut boulder = StructureTemplate::new("boulder");
+        for x in -2..=2 {
+            for y in -1..=2 {
+                for z in -2..=2 {
+                    if x * x + (y - 1) * (y - 1) * 2 + z * z <= 5 {
+                        boulder = boulder.with_block(IVec3::new(x, y, z), "stone");
+                    }
+                }
+            }
+        }
+        return boulder;
+    }
+
+    /// Crumbling walls of a small stone hut.
+    pub fn ruin() -> Self {
+        // Wall height around the ring, starting at the north west corner and going clockwise
+        const HEIGHTS: [i32; 24] = [3, 3, 2, 0, 1, 2, 3, 3, 2, 1, 1, 0, 0, 0, 1, 2, 2, 3, 2, 1, 2, 3, 1, 2];
+
+        let mut ruin = StructureTemplate::new("ruin").with_box(IVec3::new(-2, 0, -2), IVec3::new(2, 0, 2), "stone");
+        let ring = (-3..3).map(|i| IVec3::new(i, 0, -3))
+            .chain((-3..3).map(|i| IVec3::new(3, 0, i)))
+            .chain((-3..3).map(|i| IVec3::new(-i, 0, 3)))
+            .chain((-3..3).map(|i| IVec3::new(-3, 0, -i)));
+        for (pos, height) in ring.zip(HEIGHTS) {
+            for y in 0..=height {
+                ruin = ruin.with_block(pos + IVec3::new(0, y, 0), "stone");
+            }
+        }
+        return ruin;
+    }
+}
@@ -1,30 +1,162 @@
-use std::ops::Deref;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use bevy::ecs::system::CommandQueue;
-use bevy::pbr::{ExtendedMaterial, OpaqueRendererMethod};
+use bevy::app::AppExit;
 use bevy::prelude::*;
 use bevy::render::mesh::{Indices, PrimitiveTopology};
 use bevy::render::render_asset::RenderAssetUsages;
 use bevy::tasks::{AsyncComputeTaskPool, block_on, Task};
 use bevy::tasks::futures_lite::future;
 
-use crate::voxel_renderer::VoxelMaterial;
-use crate::world::{BlockGetter, VoxelWorld};
+use crate::biome::{Biome, BiomeMap};
+use crate::block::{BlockDefinitionsLoaded, BlockId, BlockPlugin, BlockRegistry, BlockRegistryChanged};
+use crate::generator::{TerrainGenerator, WorldGenerator};
+use crate::player_controller::Player;
+use crate::region::RegionStorage;
+use crate::voxel_renderer::{ATTRIBUTE_PACKED_VOXEL, ChunkMaterial, TranslucentMaterialHandle, VoxelMaterialHandle, VoxelRendererPlugin};
+use crate::world::{BlockGetter, Face, RenderChunk, VoxelWorld};
 
 pub struct VoxelPlugin;
 
+/// Marks the entity rendering one pass of the chunk at `chunk_pos`.
 #[derive(Component)]
-struct VoxelMeshTask(Task<CommandQueue>, IVec3);
+pub struct VoxelMesh {
+    pub chunk_pos: IVec3,
+    pub pass: MeshPass,
+}
 
-#[derive(Component)]
-struct VoxelMesh {
-    chunk_pos: IVec3,
+/// Every chunk is drawn in two meshes, with different materials.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum MeshPass {
+    /// The solid blocks.
+    Opaque,
+    /// Transparent blocks like glass and the surfaces of fluids, blended over what is behind them.
+    Translucent,
+}
+
+impl MeshPass {
+    pub const ALL: [MeshPass; 2] = [MeshPass::Opaque, MeshPass::Translucent];
 }
 
+/// The mesh of each pass of a chunk, as built by a meshing task.
+type ChunkMeshes = Vec<(MeshPass, Mesh)>;
+
+type ChunkMeshQuery<'w, 's> = Query<'w, 's, &'static mut Handle<Mesh>, With<VoxelMesh>>;
+
+/// The entity rendering each pass of each chunk, so a rebuilt mesh can replace the old one in place.
+#[derive(Debug, Default, Resource)]
+pub struct ChunkEntities(pub HashMap<(IVec3, MeshPass), Entity>);
+
 #[derive(Resource)]
 pub struct ClientWorld(pub Arc<RwLock<VoxelWorld>>);
 
+/// Keeps the chunks within `radius` chunks of this entity loaded.
+#[derive(Debug, Component)]
+pub struct ChunkLoader {
+    pub radius: i32,
+}
+
+/// Chunks waiting to be meshed and the meshing tasks currently running.
+///
+/// Pending chunks are started nearest to the [`Player`] first, and at most `max_in_flight` tasks run at once so a burst
+/// of edits cannot flood the task pool.
+#[derive(Resource)]
+pub struct MeshJobQueue {
+    pub max_in_flight: usize,
+    pending: HashSet<IVec3>,
+    running: HashMap<IVec3, MeshJob>,
+}
+
+struct MeshJob {
+    task: Task<ChunkMeshes>,
+    /// The [`VoxelWorld::revision`] of the chunk when its snapshot was taken.
+    revision: u64,
+}
+
+impl MeshJobQueue {
+    pub const MAX_IN_FLIGHT: usize = 8;
+
+    /// Queues the chunk to be meshed. A chunk that is already queued is only meshed once.
+    pub fn push(&mut self, chunk_pos: IVec3) {
+        self.pending.insert(chunk_pos);
+    }
+
+    /// Forgets the chunk and drops its running task, if any, which cancels it.
+    pub fn cancel(&mut self, chunk_pos: IVec3) {
+        self.pending.remove(&chunk_pos);
+        self.running.remove(&chunk_pos);
+    }
+}
+
+impl Default for MeshJobQueue {
+    fn default() -> Self {
+        Self {
+            max_in_flight: MeshJobQueue::MAX_IN_FLIGHT,
+            pending: HashSet::new(),
+            running: HashMap::new(),
+        }
+    }
+}
+
+/// The generator filling chunks that have never been saved, and the chunks being read from disk or generated.
+#[derive(Resource)]
+pub struct WorldGeneration {
+    pub generator: Arc<dyn WorldGenerator>,
+    tasks: HashMap<IVec3, Task<RenderChunk>>,
+}
+
+impl WorldGeneration {
+    pub const SEED: u32 = 0x5eed;
+
+    pub fn create(generator: impl WorldGenerator + 'static) -> Self {
+        Self {
+            generator: Arc::new(generator),
+            tasks: HashMap::new(),
+        }
+    }
+
+    /// Reads the chunk from the storage on a background task, generating it if it was never saved.
+    fn spawn(&mut self, chunk_pos: IVec3, storage: &RegionStorage, registry: &BlockRegistry) {
+        let generator = self.generator.clone();
+        let storage = storage.clone();
+        let registry = registry.clone();
+        self.tasks.insert(chunk_pos, AsyncComputeTaskPool::get().spawn(async move {
+            return match storage.read_chunk(chunk_pos) {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => generator.generate_chunk(chunk_pos, &registry),
+                Err(error) => {
+                    error!("Failed to read chunk {}, generating it instead: {}", chunk_pos, error);
+                    generator.generate_chunk(chunk_pos, &registry)
+                }
+            };
+        }));
+    }
+}
+
+/// Where the world is persisted, and the background task writing modified chunks to it.
+#[derive(Resource)]
+pub struct WorldStorage {
+    pub storage: RegionStorage,
+    autosave_timer: Timer,
+    /// Writes the chunks at the listed positions.
+    autosave_task: Option<Task<(Vec<IVec3>, io::Result<()>)>>,
+}
+
+impl WorldStorage {
+    pub const SAVE_PATH: &'static str = "saves/world";
+    pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+    fn create(storage: RegionStorage) -> Self {
+        Self {
+            storage,
+            autosave_timer: Timer::new(WorldStorage::AUTOSAVE_INTERVAL, TimerMode::Repeating),
+            autosave_task: None,
+        }
+    }
+}
+
 impl ClientWorld {
     fn create(world: VoxelWorld) -> Self {
         Self {
@@ -35,40 +167,285 @@ impl ClientWorld {
 
 impl Plugin for VoxelPlugin {
     fn build(&self, app: &mut App) {
-        let mut world = VoxelWorld::create(1);
-        world.set_block(IVec3::new(0, 1, 0), VoxelWorld::STONE);
-        world.set_block(IVec3::new(4, 0, 0), VoxelWorld::STONE);
-        world.set_block(IVec3::new(3, 0, 0), VoxelWorld::STONE);
-        world.set_block(IVec3::new(2, 0, 0), VoxelWorld::STONE);
-        world.set_block(IVec3::new(1, 0, 0), VoxelWorld::STONE);
-
-        app.add_plugins(MaterialPlugin::<ExtendedMaterial<StandardMaterial, VoxelMaterial>>::default())
-            .add_systems(Update, handle_tasks)
+        app.add_plugins(BlockPlugin);
+        let registry = app.world.resource::<BlockRegistry>().clone();
+        // Saved chunks are read as they come into range of a loader, and the rest are generated
+        let storage = RegionStorage::new(WorldStorage::SAVE_PATH);
+        let biomes = Arc::new(BiomeMap::new(WorldGeneration::SEED));
+        let mut world = VoxelWorld::create(registry);
+        world.set_biomes(biomes.clone());
+
+        app.add_plugins(VoxelRendererPlugin)
+            .add_systems(Update, (
+                // Generating before the block definitions are in would fill the terrain with the builtin blocks only
+                (update_loaded_chunks.run_if(resource_exists::<BlockDefinitionsLoaded>), handle_generation_tasks, apply_registry_changes),
+                remesh_dirty_chunks,
+                start_mesh_jobs,
+                handle_tasks,
+            ).chain())
+            .add_systems(Update, (autosave, apply_voxel_material))
+            .add_systems(Last, save_on_exit)
+            .init_resource::<MeshingSettings>()
+            .init_resource::<MeshJobQueue>()
+            .init_resource::<ChunkEntities>()
+            .insert_resource(WorldStorage::create(storage))
+            .insert_resource(WorldGeneration::create(TerrainGenerator::new(WorldGeneration::SEED, biomes)))
             .insert_resource(ClientWorld::create(world));
     }
 }
 
-fn handle_tasks(mut commands: Commands, mut transform_tasks: Query<&mut VoxelMeshTask>, chunks: Query<(Entity, &VoxelMesh)>) {
-    for mut task in &mut transform_tasks {
-        if let Some(mut commands_queue) = block_on(future::poll_once(&mut task.0)) {
-            // append the returned command queue to have it execute later
-            commands.append(&mut commands_queue);
+/// Starts the pending jobs nearest to the player, as long as there are free slots.
+fn start_mesh_jobs(mut queue: ResMut<MeshJobQueue>, client_world: Res<ClientWorld>, settings: Res<MeshingSettings>, player: Query<&GlobalTransform, With<Player>>) {
+    let free = queue.max_in_flight.saturating_sub(queue.running.len());
+    if free == 0 || queue.pending.is_empty() {
+        return;
+    }
+
+    let center = player.get_single().map_or(Vec3::ZERO, |transform| transform.translation());
+    let distance = |chunk_pos: &IVec3| ((*chunk_pos * VoxelWorld::CHUNK_SIZE as i32).as_vec3() + Vec3::splat(VoxelWorld::CHUNK_SIZE as f32 / 2.0)).distance_squared(center);
+    let mut pending: Vec<IVec3> = queue.pending.iter().copied().collect();
+    pending.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+
+    let world = client_world.0.read().unwrap();
+    for chunk_pos in pending.into_iter().take(free) {
+        queue.pending.remove(&chunk_pos);
+        if !world.is_loaded(chunk_pos) {
+            continue;
+        }
+        // Replacing a running job drops its task, cancelling it, as its result would be out of date anyway
+        queue.running.insert(chunk_pos, MeshJob {
+            task: spawn_mesh_task(&world, chunk_pos, *settings),
+            revision: world.revision(chunk_pos),
+        });
+    }
+}
+
+/// Swaps in the meshes of finished jobs, discarding those built from a snapshot that has since changed.
+fn handle_tasks(
+    mut commands: Commands,
+    mut queue: ResMut<MeshJobQueue>,
+    client_world: Res<ClientWorld>,
+    material: Res<VoxelMaterialHandle>,
+    translucent_material: Res<TranslucentMaterialHandle>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    mut chunks: ChunkMeshQuery,
+) {
+    let mut finished: Vec<(IVec3, ChunkMeshes, u64)> = Vec::new();
+    queue.running.retain(|chunk_pos, job| {
+        return match block_on(future::poll_once(&mut job.task)) {
+            Some(passes) => {
+                finished.push((*chunk_pos, passes, job.revision));
+                false
+            }
+            None => true,
+        };
+    });
+    if finished.is_empty() {
+        return;
+    }
 
-            for (entity, mesh) in chunks.iter() {
-                if mesh.chunk_pos == task.1 {
-                    commands.entity(entity).despawn();
+    let world = client_world.0.read().unwrap();
+    for (chunk_pos, passes, revision) in finished {
+        if !world.is_loaded(chunk_pos) || world.revision(chunk_pos) != revision {
+            continue;
+        }
+        for (pass, mesh) in passes {
+            if mesh.count_vertices() == 0 {
+                despawn_chunk_mesh(&mut commands, &mut chunk_entities, &mut meshes, &chunks, chunk_pos, pass);
+                continue;
+            }
+            let existing = chunk_entities.0.get(&(chunk_pos, pass)).and_then(|entity| chunks.get_mut(*entity).ok());
+            match existing {
+                // Swapping the handle on the live entity means there is never a frame without either mesh
+                Some(mut handle) => {
+                    let old = std::mem::replace(&mut *handle, meshes.add(mesh));
+                    meshes.remove(&old);
+                }
+                None => {
+                    let material = match pass {
+                        MeshPass::Opaque => &material.0,
+                        MeshPass::Translucent => &translucent_material.0,
+                    };
+                    let entity = spawn_chunk_mesh(&mut commands, &mut meshes, material, chunk_pos, pass, mesh);
+                    chunk_entities.0.insert((chunk_pos, pass), entity);
                 }
             }
         }
     }
 }
 
-pub fn schedule(mut commands: Commands, voxel_world: Arc<RwLock<dyn BlockGetter>>, chunk_pos: IVec3) {
+/// Moves every chunk over to the shared materials when their handles are replaced.
+fn apply_voxel_material(material: Res<VoxelMaterialHandle>, translucent_material: Res<TranslucentMaterialHandle>, mut chunks: Query<(&mut Handle<ChunkMaterial>, &VoxelMesh)>) {
+    let opaque_changed = material.is_changed() && !material.is_added();
+    let translucent_changed = translucent_material.is_changed() && !translucent_material.is_added();
+    if !opaque_changed && !translucent_changed {
+        return;
+    }
+    for (mut handle, mesh) in &mut chunks {
+        match mesh.pass {
+            MeshPass::Opaque if opaque_changed => *handle = material.0.clone(),
+            MeshPass::Translucent if translucent_changed => *handle = translucent_material.0.clone(),
+            _ => {}
+        }
+    }
+}
+
+fn update_loaded_chunks(mut commands: Commands, client_world: Res<ClientWorld>, world_storage: Res<WorldStorage>, mut generation: ResMut<WorldGeneration>, registry: Res<BlockRegistry>, mut queue: ResMut<MeshJobQueue>, mut chunk_entities: ResMut<ChunkEntities>, mut meshes: ResMut<Assets<Mesh>>, loaders: Query<(&GlobalTransform, &ChunkLoader)>, chunks: ChunkMeshQuery) {
+    let mut required: HashSet<IVec3> = HashSet::new();
+    let mut retained: HashSet<IVec3> = HashSet::new();
+    for (transform, loader) in loaders.iter() {
+        let center = VoxelWorld::chunk_pos(transform.translation().floor().as_ivec3());
+        // Chunks are only unloaded once they are one chunk past the load radius so walking back and forth over a border does not thrash
+        let radius = loader.radius + 1;
+        for z in -radius..=radius {
+            for y in -radius..=radius {
+                for x in -radius..=radius {
+                    let offset = IVec3::new(x, y, z);
+                    retained.insert(center + offset);
+                    if offset.abs().max_element() < radius {
+                        required.insert(center + offset);
+                    }
+                }
+            }
+        }
+    }
+
+    // Dropping the task of a chunk that went out of range cancels its generation
+    generation.tasks.retain(|chunk_pos, _| retained.contains(chunk_pos));
+    {
+        let world = client_world.0.read().unwrap();
+        if required.iter().all(|chunk_pos| world.is_loaded(*chunk_pos) || generation.tasks.contains_key(chunk_pos)) && world.get_chunks().all(|(chunk_pos, _)| retained.contains(&chunk_pos) || world.is_unsaved(chunk_pos)) {
+            return;
+        }
+    }
+
+    let mut world = client_world.0.write().unwrap();
+    // Modified chunks stay loaded until the autosave has written them
+    let unloaded: Vec<IVec3> = world.get_chunks().map(|(chunk_pos, _)| chunk_pos).filter(|chunk_pos| !retained.contains(chunk_pos) && !world.is_unsaved(*chunk_pos)).collect();
+    for chunk_pos in unloaded {
+        world.unload_chunk(chunk_pos);
+        queue.cancel(chunk_pos);
+        for pass in MeshPass::ALL {
+            despawn_chunk_mesh(&mut commands, &mut chunk_entities, &mut meshes, &chunks, chunk_pos, pass);
+        }
+    }
+    for chunk_pos in required {
+        if !world.is_loaded(chunk_pos) && !generation.tasks.contains_key(&chunk_pos) {
+            generation.spawn(chunk_pos, &world_storage.storage, &registry);
+        }
+    }
+}
+
+/// Loads the chunks that finished reading or generating into the world.
+fn handle_generation_tasks(client_world: Res<ClientWorld>, mut generation: ResMut<WorldGeneration>) {
+    let mut generated: Vec<(IVec3, RenderChunk)> = Vec::new();
+    generation.tasks.retain(|chunk_pos, task| {
+        return match block_on(future::poll_once(task)) {
+            Some(chunk) => {
+                generated.push((*chunk_pos, chunk));
+                false
+            }
+            None => true,
+        };
+    });
+    if generated.is_empty() {
+        return;
+    }
+
+    let mut world = client_world.0.write().unwrap();
+    for (chunk_pos, chunk) in generated {
+        world.load_chunk(chunk_pos, chunk);
+    }
+}
+
+/// Rebuilds the mesh of every chunk marked dirty since the last frame, once per chunk no matter how many of its blocks changed.
+fn remesh_dirty_chunks(client_world: Res<ClientWorld>, mut queue: ResMut<MeshJobQueue>) {
+    for chunk_pos in client_world.0.write().unwrap().take_dirty() {
+        queue.push(chunk_pos);
+    }
+}
+
+/// Periodically writes the modified chunks to disk on a background task.
+fn autosave(time: Res<Time>, client_world: Res<ClientWorld>, mut world_storage: ResMut<WorldStorage>) {
+    if let Some(task) = &mut world_storage.autosave_task {
+        let Some((saved, result)) = block_on(future::poll_once(task)) else {
+            return;
+        };
+        if let Err(error) = &result {
+            error!("Failed to autosave world: {}", error);
+        }
+        client_world.0.write().unwrap().finish_save(&saved, result.is_ok());
+        world_storage.autosave_task = None;
+    }
+
+    if !world_storage.autosave_timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let chunks = client_world.0.write().unwrap().take_unsaved();
+    if chunks.is_empty() {
+        return;
+    }
+
+    let storage = world_storage.storage.clone();
+    world_storage.autosave_task = Some(AsyncComputeTaskPool::get().spawn(async move {
+        let result = storage.write_chunks(&chunks);
+        return (chunks.into_iter().map(|(chunk_pos, _)| chunk_pos).collect(), result);
+    }));
+}
+
+fn save_on_exit(mut exit: EventReader<AppExit>, client_world: Res<ClientWorld>, mut world_storage: ResMut<WorldStorage>) {
+    if exit.read().next().is_none() {
+        return;
+    }
+
+    let mut world = client_world.0.write().unwrap();
+    // Chunks the last autosave failed to write are unsaved again and written below
+    if let Some(task) = world_storage.autosave_task.take() {
+        let (saved, result) = block_on(task);
+        if let Err(error) = &result {
+            error!("Failed to autosave world: {}", error);
+        }
+        world.finish_save(&saved, result.is_ok());
+    }
+    let chunks = world.take_unsaved();
+    if let Err(error) = world_storage.storage.write_chunks(&chunks) {
+        error!("Failed to save world: {}", error);
+    }
+}
+
+/// Hands the rebuilt registry to the world and remeshes every chunk containing a block that changed.
+fn apply_registry_changes(mut events: EventReader<BlockRegistryChanged>, registry: Res<BlockRegistry>, client_world: Res<ClientWorld>) {
+    let mut changed: HashSet<BlockId> = HashSet::new();
+    for event in events.read() {
+        changed.extend(event.changed.iter().copied());
+    }
+    if changed.is_empty() {
+        return;
+    }
+
+    let mut world = client_world.0.write().unwrap();
+    world.set_registry(registry.clone());
+    let affected: Vec<IVec3> = world.get_chunks()
+        .filter(|(_, chunk)| changed.iter().any(|block| chunk.contains(*block)))
+        .map(|(chunk_pos, _)| chunk_pos)
+        .collect();
+    for chunk_pos in affected {
+        world.mark_dirty(chunk_pos);
+    }
+}
+
+/// Starts building the mesh of a chunk in the background. The task works on a snapshot of the chunk and its neighbours,
+/// so edits made to the world while it runs are picked up by the next rebuild.
+fn spawn_mesh_task(voxel_world: &VoxelWorld, chunk_pos: IVec3, settings: MeshingSettings) -> Task<ChunkMeshes> {
     let thread_pool = AsyncComputeTaskPool::get();
-    let entity = commands.spawn_empty().id();
+    let snapshot = voxel_world.snapshot(chunk_pos);
 
-    let task = thread_pool.spawn_local(async move {
-        let mesh = build_mesh(voxel_world.read().unwrap().deref(), chunk_pos * VoxelWorld::CHUNK_SIZE as i32);
+    return thread_pool.spawn(async move {
+        let mesh = vec![
+            (MeshPass::Opaque, build_mesh(&snapshot, snapshot.origin(), settings)),
+            (MeshPass::Translucent, build_translucent_mesh(&snapshot, snapshot.origin())),
+        ];
         // let mesh = {
         //     let positions = vec![
         //         Vec3::new(16.0, 0.0, 0.0),
@@ -87,46 +464,32 @@ pub fn schedule(mut commands: Commands, voxel_world: Arc<RwLock<dyn BlockGetter>
         //         .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
         // };
 
-        let mut command_queue = CommandQueue::default();
-
-        // we use a raw command queue to pass a FnOne(&mut World) back to be
-        // applied in a deferred manner.
-        command_queue.push(move |world: &mut World| {
-            let mesh = world.get_resource_mut::<Assets<Mesh>>().unwrap().add(mesh);
-            let material = world.get_resource_mut::<Assets<ExtendedMaterial<StandardMaterial, VoxelMaterial>>>().unwrap().add(ExtendedMaterial {
-                base: StandardMaterial {
-                    base_color: Color::RED,
-                    perceptual_roughness: 0.8,
-                    // can be used in forward or deferred mode.
-                    opaque_render_method: OpaqueRendererMethod::Auto,
-                    // in deferred mode, only the PbrInput can be modified (uvs, color and other material properties),
-                    // in forward mode, the output can also be modified after lighting is applied.
-                    // see the fragment shader `extended_material.wgsl` for more info.
-                    // Note: to run in deferred mode, you must also add a `DeferredPrepass` component to the camera and either
-                    // change the above to `OpaqueRendererMethod::Deferred` or add the `DefaultOpaqueRendererMethod` resource.
-                    ..Default::default()
-                },
-                extension: VoxelMaterial { quantize_steps: 20 },
-            });
-
-            world
-                .entity_mut(entity)
-                // Add our new PbrBundle of components to our tagged entity
-                .insert((MaterialMeshBundle {
-                    mesh,
-                    material,
-                    ..default()
-                }, VoxelMesh {
-                    chunk_pos
-                }))
-                // Task is complete, so remove task component from entity
-                .remove::<VoxelMeshTask>();
-        });
-
-        command_queue
+        mesh
     });
+}
 
-    commands.entity(entity).insert(VoxelMeshTask(task, chunk_pos));
+/// Spawns the entity rendering a finished chunk mesh.
+fn spawn_chunk_mesh(commands: &mut Commands, meshes: &mut Assets<Mesh>, material: &Handle<ChunkMaterial>, chunk_pos: IVec3, pass: MeshPass, mesh: Mesh) -> Entity {
+    return commands.spawn((MaterialMeshBundle {
+        mesh: meshes.add(mesh),
+        material: material.clone(),
+        transform: Transform::from_translation((chunk_pos * VoxelWorld::CHUNK_SIZE as i32).as_vec3()),
+        ..default()
+    }, VoxelMesh {
+        chunk_pos,
+        pass,
+    })).id();
+}
+
+/// Despawns the entity rendering one pass of the chunk, if there is one, and frees its mesh.
+fn despawn_chunk_mesh(commands: &mut Commands, chunk_entities: &mut ChunkEntities, meshes: &mut Assets<Mesh>, chunks: &ChunkMeshQuery, chunk_pos: IVec3, pass: MeshPass) {
+    let Some(entity) = chunk_entities.0.remove(&(chunk_pos, pass)) else {
+        return;
+    };
+    if let Ok(mesh) = chunks.get(entity) {
+        meshes.remove(mesh);
+    }
+    commands.entity(entity).despawn();
 }
 
 // DOWN(0, 1, -1, "down", Direction.AxisDirection.NEGATIVE, Direction.Axis.Y, new Vec3i(0, -1, 0)),
@@ -135,137 +498,314 @@ pub fn schedule(mut commands: Commands, voxel_world: Arc<RwLock<dyn BlockGetter>
 // SOUTH(3, 2, 0, "south", Direction.AxisDirection.POSITIVE, Direction.Axis.Z, new Vec3i(0, 0, 1)),
 // WEST(4, 5, 1, "west", Direction.AxisDirection.NEGATIVE, Direction.Axis.X, new Vec3i(-1, 0, 0)),
 // EAST(5, 4, 3, "east", Direction.AxisDirection.POSITIVE, Direction.Axis.X, new Vec3i(1, 0, 0));
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum MeshingMode {
+    /// One quad for every visible block face.
+    #[default]
+    Naive,
+    /// Merges adjacent coplanar faces of the same block into larger quads.
+    Greedy,
+}
+
+/// How chunk mesh vertices are laid out on the GPU.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum VoxelVertexFormat {
+    /// Separate f32 positions, normals, uvs and colors, 56 bytes per vertex.
+    Standard,
+    /// Everything in [`ATTRIBUTE_PACKED_VOXEL`], 8 bytes per vertex.
+    #[default]
+    Packed,
+}
 
-fn build_mesh(world: &dyn BlockGetter, start_pos: IVec3) -> Mesh {
-    let mut positions: Vec<Vec3> = Vec::new();
-    let mut normals: Vec<Vec3> = Vec::new();
-    let mut indices: Vec<u32> = Vec::new();
+#[derive(Debug, Default, Copy, Clone, Resource)]
+pub struct MeshingSettings {
+    pub mode: MeshingMode,
+    pub vertex_format: VoxelVertexFormat,
+}
+
+/// Brightness for each ambient occlusion level, from fully occluded to unoccluded.
+const AO_CURVE: [f32; 4] = [0.35, 0.55, 0.75, 1.0];
+
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    // Ambient occlusion and the texture layer are carried in the second uv channel so the standard vertex shaders
+    // pass them through to voxel.wgsl
+    occlusion_layers: Vec<Vec2>,
+    colors: Vec<[f32; 4]>,
+    packed: Vec<[u32; 2]>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    /// Adds a `width` by `height` quad covering the face of the block at `pos`, relative to the chunk origin.
+    fn push_quad(&mut self, face: Face, pos: IVec3, width: i32, height: i32, ao: [u8; 4], layer: u32, color: Color) {
+        let normal = face.normal();
+        let (u, v) = face.axes();
+        let origin = pos + normal.max(IVec3::ZERO);
+        let count = self.positions.len() as u32;
+
+        self.positions.push(origin.as_vec3());
+        self.positions.push((origin + u * width).as_vec3());
+        self.positions.push((origin + u * width + v * height).as_vec3());
+        self.positions.push((origin + v * height).as_vec3());
+        self.uvs.push(texture_uv(face, 0, 0));
+        self.uvs.push(texture_uv(face, width, 0));
+        self.uvs.push(texture_uv(face, width, height));
+        self.uvs.push(texture_uv(face, 0, height));
+        for corner in ao {
+            self.normals.push(normal.as_vec3());
+            self.occlusion_layers.push(Vec2::new(AO_CURVE[corner as usize], layer as f32));
+            self.colors.push(color.as_linear_rgba_f32());
+        }
+        let color = u32::from_le_bytes(color.as_linear_rgba_f32().map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8));
+        for (i, corner) in ao.into_iter().enumerate() {
+            let position = self.positions[count as usize + i].as_uvec3();
+            let packed = position.x | position.y << 5 | position.z << 10 | (face as u32) << 15 | (corner as u32) << 18 | layer.min(0xfff) << 20;
+            self.packed.push([packed, color]);
+        }
 
-    let mut count = 0;
+        // Split the quad along the diagonal between its darker corners so the occlusion gradient stays symmetric
+        if ao[0] + ao[2] > ao[1] + ao[3] {
+            self.indices.extend_from_slice(&[count + 1, count + 2, count + 3, count + 1, count + 3, count]);
+        } else {
+            self.indices.extend_from_slice(&[count, count + 1, count + 2, count, count + 2, count + 3]);
+        }
+    }
+
+    /// Adds an unoccluded 1 by 1 quad like [`MeshBuilder::push_quad`], with the corners at the bottom of the block
+    /// moved to height `bottom` and those at its top to `top`. Only the standard vertex format can hold the moved
+    /// positions.
+    fn push_fluid_quad(&mut self, face: Face, pos: IVec3, bottom: f32, top: f32, layer: u32, color: Color) {
+        let count = self.positions.len();
+        self.push_quad(face, pos, 1, 1, [3; 4], layer, color);
+        for position in &mut self.positions[count..] {
+            position.y = if position.y == pos.y as f32 { bottom } else { top };
+        }
+    }
+
+    fn quad_count(&self) -> usize {
+        return self.indices.len() / 6;
+    }
+
+    fn build(self, format: VoxelVertexFormat) -> Mesh {
+        let mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        ).with_inserted_indices(Indices::U32(self.indices));
+        if format == VoxelVertexFormat::Packed {
+            return mesh.with_inserted_attribute(ATTRIBUTE_PACKED_VOXEL, self.packed);
+        }
+        return mesh
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_1, self.occlusion_layers)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, self.colors);
+    }
+}
+
+/// Texture coordinates, in blocks, of a point `du` and `dv` blocks along the face axes. Side faces keep the texture
+/// upright and none of the faces are mirrored when seen from outside the block.
+fn texture_uv(face: Face, du: i32, dv: i32) -> Vec2 {
+    let (s, t) = match face {
+        Face::Up => (dv, du),
+        Face::Down => (du, dv),
+        Face::South | Face::West => (du, -dv),
+        Face::North | Face::East => (-dv, -du),
+    };
+    return Vec2::new(s as f32, t as f32);
+}
+
+/// Ambient occlusion at each corner of a block face, in quad vertex order, from 0 (fully occluded) to 3 (unoccluded).
+///
+/// Each corner is darkened by the two blocks beside it and the one diagonal to it in the layer in front of the face.
+fn face_ao(world: &dyn BlockGetter, pos: IVec3, face: Face) -> [u8; 4] {
+    let (u, v) = face.axes();
+    let front = pos + face.normal();
+    let occludes = |offset: IVec3| world.get_properties(front + offset).opaque;
+    let corner = |du: i32, dv: i32| -> u8 {
+        let side1 = occludes(u * du);
+        let side2 = occludes(v * dv);
+        if side1 && side2 {
+            return 0;
+        }
+        return 3 - side1 as u8 - side2 as u8 - occludes(u * du + v * dv) as u8;
+    };
+    return [corner(-1, -1), corner(1, -1), corner(1, 1), corner(-1, 1)];
+}
+
+/// The biome tinting a face, if the block is tinted. Only the top face is, so the sides and bottom keep their own color.
+fn face_tint(world: &dyn BlockGetter, pos: IVec3, block: BlockId, face: Face) -> Option<Biome> {
+    if face != Face::Up || !world.registry().get(block).tinted {
+        return None;
+    }
+    return Some(world.biome_at(pos));
+}
+
+/// The vertex color of a face, which the texture is multiplied with. Textures already carry the colors of their block,
+/// so the block color only shows on untextured faces, and tinted faces are colored by their biome alone.
+fn face_color(world: &dyn BlockGetter, block: BlockId, layer: u32, tint: Option<Biome>) -> Color {
+    if let Some(biome) = tint {
+        return biome.tint();
+    }
+    return if layer == 0 { world.registry().get(block).color } else { Color::WHITE };
+}
+
+fn build_mesh(world: &dyn BlockGetter, start_pos: IVec3, settings: MeshingSettings) -> Mesh {
+    let start = Instant::now();
+    let mut builder = MeshBuilder::default();
+    match settings.mode {
+        MeshingMode::Naive => build_naive(world, start_pos, &mut builder),
+        MeshingMode::Greedy => build_greedy(world, start_pos, &mut builder),
+    }
+    debug!("Built {:?} mesh for {} with {} quads in {:?}", settings.mode, start_pos, builder.quad_count(), start.elapsed());
+    return builder.build(settings.vertex_format);
+}
+
+/// Meshes the blocks of a chunk that are blended over what is behind them, one quad per visible face. These are
+/// transparent blocks like glass and fluid surfaces. The top of a fluid sits lower the lower its level, unless more of
+/// the same fluid is resting on it.
+fn build_translucent_mesh(world: &dyn BlockGetter, start_pos: IVec3) -> Mesh {
+    let mut builder = MeshBuilder::default();
     for z in 0..VoxelWorld::CHUNK_SIZE as i32 {
         for y in 0..VoxelWorld::CHUNK_SIZE as i32 {
             for x in 0..VoxelWorld::CHUNK_SIZE as i32 {
-                let pos = start_pos + IVec3::new(x, y, z);
-                if !world.should_render_block(pos) {
-                    continue;
+                let pos = IVec3::new(x, y, z);
+                let block = world.get_block(start_pos + pos);
+                let properties = world.registry().get(block);
+                if properties.transparent {
+                    for face in Face::ALL {
+                        if world.should_render_face(start_pos + pos, face.normal()) {
+                            let layer = world.registry().texture_layer(block, face);
+                            builder.push_quad(face, pos, 1, 1, face_ao(world, start_pos + pos, face), layer, face_color(world, block, layer, face_tint(world, start_pos + pos, block, face)));
+                        }
+                    }
                 }
+                let Some(fluid) = properties.fluid else {
+                    continue;
+                };
 
-                let mut rendered_count = 0;
-
-                // UP
-                if world.should_render_face(pos, IVec3::new(0, 1, 0)) {
-                    positions.push(Vec3::new((x + 1) as f32, (y + 1) as f32, z as f32));
-                    positions.push(Vec3::new(x as f32, (y + 1) as f32, z as f32));
-                    positions.push(Vec3::new(x as f32, (y + 1) as f32, (z + 1) as f32));
-                    positions.push(Vec3::new((x + 1) as f32, (y + 1) as f32, (z + 1) as f32));
-
-                    normals.push(Vec3::new(0.0, 1.0, 0.0));
-                    normals.push(Vec3::new(0.0, 1.0, 0.0));
-                    normals.push(Vec3::new(0.0, 1.0, 0.0));
-                    normals.push(Vec3::new(0.0, 1.0, 0.0));
-                    rendered_count += 1;
+                // The height of the surface in a block of the same fluid, relative to the chunk
+                let surface_at = |pos: IVec3| -> Option<f32> {
+                    let other = world.get_properties(start_pos + pos).fluid.filter(|other| other.source == fluid.source)?;
+                    if world.get_properties(start_pos + pos + IVec3::Y).fluid.is_some_and(|above| above.source == fluid.source) {
+                        return Some((pos.y + 1) as f32);
+                    }
+                    return Some(pos.y as f32 + other.level as f32 / (other.max_level + 1) as f32);
+                };
+                let surface = surface_at(pos).unwrap();
+                for face in Face::ALL {
+                    let neighbour = pos + face.normal();
+                    if world.get_properties(start_pos + neighbour).opaque {
+                        continue;
+                    }
+                    // Next to the same fluid, only the part of a side above the neighbour's lower surface is visible
+                    let bottom = match surface_at(neighbour) {
+                        Some(_) if face.normal().y != 0 => continue,
+                        Some(neighbour_surface) => neighbour_surface,
+                        None => y as f32,
+                    };
+                    if bottom >= surface {
+                        continue;
+                    }
+                    let layer = world.registry().texture_layer(block, face);
+                    let color = face_color(world, block, layer, None);
+                    builder.push_fluid_quad(face, pos, bottom, surface, layer, color);
+                    // Back faces are culled, so the surface needs a second quad to be seen from below
+                    if face == Face::Up {
+                        builder.push_fluid_quad(Face::Down, pos, surface, surface, layer, color);
+                    }
                 }
+            }
+        }
+    }
+    return builder.build(VoxelVertexFormat::Standard);
+}
 
-                // DOWN
-                if world.should_render_face(pos, IVec3::new(0, -1, 0)) {
-                    positions.push(Vec3::new(x as f32, y as f32, z as f32));
-                    positions.push(Vec3::new((x + 1) as f32, y as f32, z as f32));
-                    positions.push(Vec3::new((x + 1) as f32, y as f32, (z + 1) as f32));
-                    positions.push(Vec3::new(x as f32, y as f32, (z + 1) as f32));
-
-                    normals.push(Vec3::new(0.0, -1.0, 0.0));
-                    normals.push(Vec3::new(0.0, -1.0, 0.0));
-                    normals.push(Vec3::new(0.0, -1.0, 0.0));
-                    normals.push(Vec3::new(0.0, -1.0, 0.0));
-                    rendered_count += 1;
+fn build_naive(world: &dyn BlockGetter, start_pos: IVec3, builder: &mut MeshBuilder) {
+    for z in 0..VoxelWorld::CHUNK_SIZE as i32 {
+        for y in 0..VoxelWorld::CHUNK_SIZE as i32 {
+            for x in 0..VoxelWorld::CHUNK_SIZE as i32 {
+                let pos = IVec3::new(x, y, z);
+                // Transparent blocks are drawn by the translucent pass
+                if !world.should_render_block(start_pos + pos) || world.get_properties(start_pos + pos).transparent {
+                    continue;
                 }
 
-                // EAST
-                if world.should_render_face(pos, IVec3::new(1, 0, 0)) {
-                    positions.push(Vec3::new((x + 1) as f32, y as f32, (z + 1) as f32));
-                    positions.push(Vec3::new((x + 1) as f32, y as f32, z as f32));
-                    positions.push(Vec3::new((x + 1) as f32, (y + 1) as f32, z as f32));
-                    positions.push(Vec3::new((x + 1) as f32, (y + 1) as f32, (z + 1) as f32));
-
-                    normals.push(Vec3::new(1.0, 0.0, 0.0));
-                    normals.push(Vec3::new(1.0, 0.0, 0.0));
-                    normals.push(Vec3::new(1.0, 0.0, 0.0));
-                    normals.push(Vec3::new(1.0, 0.0, 0.0));
-                    rendered_count += 1;
+                let block = world.get_block(start_pos + pos);
+                for face in Face::ALL {
+                    if world.should_render_face(start_pos + pos, face.normal()) {
+                        let layer = world.registry().texture_layer(block, face);
+                        builder.push_quad(face, pos, 1, 1, face_ao(world, start_pos + pos, face), layer, face_color(world, block, layer, face_tint(world, start_pos + pos, block, face)));
+                    }
                 }
+            }
+        }
+    }
+}
 
-                // WEST
-                if world.should_render_face(pos, IVec3::new(-1, 0, 0)) {
-                    positions.push(Vec3::new(x as f32, y as f32, z as f32));
-                    positions.push(Vec3::new(x as f32, y as f32, (z + 1) as f32));
-                    positions.push(Vec3::new(x as f32, (y + 1) as f32, (z + 1) as f32));
-                    positions.push(Vec3::new(x as f32, (y + 1) as f32, z as f32));
-
-                    normals.push(Vec3::new(-1.0, 0.0, 0.0));
-                    normals.push(Vec3::new(-1.0, 0.0, 0.0));
-                    normals.push(Vec3::new(-1.0, 0.0, 0.0));
-                    normals.push(Vec3::new(-1.0, 0.0, 0.0));
-                    rendered_count += 1;
+fn build_greedy(world: &dyn BlockGetter, start_pos: IVec3, builder: &mut MeshBuilder) {
+    const SIZE: usize = VoxelWorld::CHUNK_SIZE;
+
+    for face in Face::ALL {
+        let normal = face.normal();
+        let depth_axis = normal.abs();
+        let (u, v) = face.axes();
+
+        for depth in 0..SIZE as i32 {
+            // The block whose face is visible at each cell of this slice. Faces only merge when their corners are
+            // equally occluded and they are tinted by the same biome, otherwise the occlusion or tint would be
+            // stretched across the merged quad.
+            let mut mask: [Option<(BlockId, [u8; 4], Option<Biome>)>; SIZE * SIZE] = [None; SIZE * SIZE];
+            for j in 0..SIZE {
+                for i in 0..SIZE {
+                    let pos = start_pos + depth_axis * depth + u * i as i32 + v * j as i32;
+                    if world.should_render_face(pos, normal) && !world.get_properties(pos).transparent {
+                        let block = world.get_block(pos);
+                        mask[i + j * SIZE] = Some((block, face_ao(world, pos, face), face_tint(world, pos, block, face)));
+                    }
                 }
+            }
 
-                // NORTH
-                if world.should_render_face(pos, IVec3::new(0, 0, -1)) {
-                    positions.push(Vec3::new(x as f32, y as f32, z as f32));
-                    positions.push(Vec3::new(x as f32, (y + 1) as f32, z as f32));
-                    positions.push(Vec3::new((x + 1) as f32, (y + 1) as f32, z as f32));
-                    positions.push(Vec3::new((x + 1) as f32, y as f32, z as f32));
-
-                    normals.push(Vec3::new(0.0, 0.0, -1.0));
-                    normals.push(Vec3::new(0.0, 0.0, -1.0));
-                    normals.push(Vec3::new(0.0, 0.0, -1.0));
-                    normals.push(Vec3::new(0.0, 0.0, -1.0));
-                    rendered_count += 1;
-                }
+            for j in 0..SIZE {
+                let mut i = 0;
+                while i < SIZE {
+                    let Some(cell) = mask[i + j * SIZE] else {
+                        i += 1;
+                        continue;
+                    };
+
+                    let mut width = 1;
+                    while i + width < SIZE && mask[i + width + j * SIZE] == Some(cell) {
+                        width += 1;
+                    }
 
-                // SOUTH
-                if world.should_render_face(pos, IVec3::new(0, 0, 1)) {
-                    positions.push(Vec3::new((x + 1) as f32, y as f32, (z + 1) as f32));
-                    positions.push(Vec3::new((x + 1) as f32, (y + 1) as f32, (z + 1) as f32));
-                    positions.push(Vec3::new(x as f32, (y + 1) as f32, (z + 1) as f32));
-                    positions.push(Vec3::new(x as f32, y as f32, (z + 1) as f32));
-
-                    normals.push(Vec3::new(0.0, 0.0, 1.0));
-                    normals.push(Vec3::new(0.0, 0.0, 1.0));
-                    normals.push(Vec3::new(0.0, 0.0, 1.0));
-                    normals.push(Vec3::new(0.0, 0.0, 1.0));
-                    rendered_count += 1;
-                }
+                    let mut height = 1;
+                    'grow: while j + height < SIZE {
+                        for k in 0..width {
+                            if mask[i + k + (j + height) * SIZE] != Some(cell) {
+                                break 'grow;
+                            }
+                        }
+                        height += 1;
+                    }
 
-                if rendered_count > 0 {
-                    for _ in 0..rendered_count {
-                        indices.push(count);
-                        indices.push(count + 1);
-                        indices.push(count + 2);
-                        indices.push(count);
-                        indices.push(count + 2);
-                        indices.push(count + 3);
-                        count += 4;
+                    for h in 0..height {
+                        for k in 0..width {
+                            mask[i + k + (j + h) * SIZE] = None;
+                        }
                     }
+
+                    let layer = world.registry().texture_layer(cell.0, face);
+                    builder.push_quad(face, depth_axis * depth + u * i as i32 + v * j as i32, width as i32, height as i32, cell.1, layer, face_color(world, cell.0, layer, cell.2));
+                    i += width;
                 }
             }
         }
     }
-
-    // let positions = vec![
-    //     Vec3::new(16.0, 0.0, 0.0),
-    //     Vec3::new(0.0, 0.0, 0.0),
-    //     Vec3::new(0.0, 0.0, 16.0),
-    //     Vec3::new(16.0, 0.0, 16.0),
-    // ];
-    // let normals = vec![Vec3::new(0.0, 1.0, 0.0); 4];
-    // let indices = Indices::U32(vec![0, 1, 2, 0, 2, 3]);
-
-    return Mesh::new(
-        PrimitiveTopology::TriangleList,
-        RenderAssetUsages::default(),
-    ).with_inserted_indices(Indices::U32(indices))
-        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
-        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
 }
 
 // fn create_voxel_mesh(mut task_executor: AsyncTaskRunner<Mesh>) {
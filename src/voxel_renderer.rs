@@ -1,11 +1,27 @@
-use bevy::asset::Asset;
-use bevy::pbr::{MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline, MaterialPipeline, MaterialPipelineKey};
-use bevy::prelude::{AlphaMode, Color, Image, Material, Reflect};
-use bevy::reflect::TypePath;
-use bevy::render::mesh::MeshVertexBufferLayout;
-use bevy::render::render_resource::{AsBindGroup, Face, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError};
+use bevy::app::{App, Plugin, Update};
+use bevy::asset::{Asset, AssetEvent, Assets, AssetServer, Handle, LoadState};
+use bevy::log::warn;
+use bevy::pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline, MaterialPlugin, MESH_SHADER_HANDLE, OpaqueRendererMethod, PREPASS_SHADER_HANDLE, StandardMaterial};
+use bevy::prelude::{AlphaMode, Color, default, DetectChanges, EventReader, FromWorld, Image, IntoSystemConfigs, Reflect, Res, ResMut, Resource, World};
+use bevy::render::mesh::{MeshVertexAttribute, MeshVertexBufferLayout};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{AsBindGroup, Extent3d, Face, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension, VertexFormat};
+use bevy::render::texture::{ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor};
+
+use crate::block::BlockRegistry;
 
 const SHADER_ASSET_PATH: &str = "shaders/voxel.wgsl";
+const VERTEX_SHADER_ASSET_PATH: &str = "shaders/voxel_vertex.wgsl";
+
+/// A whole chunk mesh vertex in two words, used instead of the standard attributes.
+///
+/// The first word holds the position within the chunk in three 5 bit fields, the [`crate::world::Face`] in 3 bits,
+/// the ambient occlusion level in 2 bits and the texture layer in the remaining 12. The second word is the block color
+/// as linear RGBA8.
+pub const ATTRIBUTE_PACKED_VOXEL: MeshVertexAttribute = MeshVertexAttribute::new("Vertex_PackedVoxel", 1_870_562_147, VertexFormat::Uint32x2);
+
+/// Width and height of every block texture, in pixels.
+pub const TEXTURE_SIZE: u32 = 16;
 
 #[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
 pub struct VoxelMaterial {
@@ -13,9 +29,20 @@ pub struct VoxelMaterial {
     // so we start from binding slot 100, leaving slots 0-99 for the base material.
     #[uniform(100)]
     pub quantize_steps: u32,
+    #[texture(101, dimension = "2d_array")]
+    #[sampler(102)]
+    pub textures: Handle<Image>,
 }
 
 impl MaterialExtension for VoxelMaterial {
+    fn vertex_shader() -> ShaderRef {
+        VERTEX_SHADER_ASSET_PATH.into()
+    }
+
+    fn prepass_vertex_shader() -> ShaderRef {
+        VERTEX_SHADER_ASSET_PATH.into()
+    }
+
     fn fragment_shader() -> ShaderRef {
         SHADER_ASSET_PATH.into()
     }
@@ -24,8 +51,204 @@ impl MaterialExtension for VoxelMaterial {
         SHADER_ASSET_PATH.into()
     }
 
-    fn specialize(pipeline: &MaterialExtensionPipeline, descriptor: &mut RenderPipelineDescriptor, layout: &MeshVertexBufferLayout, key: MaterialExtensionKey<Self>) -> Result<(), SpecializedMeshPipelineError> {
+    fn specialize(_pipeline: &MaterialExtensionPipeline, descriptor: &mut RenderPipelineDescriptor, layout: &MeshVertexBufferLayout, _key: MaterialExtensionKey<Self>) -> Result<(), SpecializedMeshPipelineError> {
         descriptor.primitive.cull_mode = Some(Face::Back);
+
+        // Packed meshes are unpacked by voxel_vertex.wgsl, in the main pass as well as the prepass. It outputs uvs and
+        // colors, so the fragment shaders are told they exist even though the mesh has neither. Normal and deferred
+        // prepasses still ask the mesh for normals and cannot draw packed meshes.
+        if layout.contains(ATTRIBUTE_PACKED_VOXEL) {
+            descriptor.vertex.buffers = vec![layout.get_layout(&[ATTRIBUTE_PACKED_VOXEL.at_shader_location(0)])?];
+            let defs = ["VERTEX_UVS", "VERTEX_UVS_B", "VERTEX_COLORS"];
+            descriptor.vertex.shader_defs.extend(defs.map(Into::into));
+            if let Some(fragment) = &mut descriptor.fragment {
+                fragment.shader_defs.extend(defs.map(Into::into));
+            }
+        } else if descriptor.vertex.shader_defs.contains(&"PREPASS_PIPELINE".into()) {
+            descriptor.vertex.shader = PREPASS_SHADER_HANDLE;
+        } else {
+            descriptor.vertex.shader = MESH_SHADER_HANDLE;
+        }
         return Ok(());
     }
-}
\ No newline at end of file
+}
+
+/// The material every chunk is rendered with.
+pub type ChunkMaterial = ExtendedMaterial<StandardMaterial, VoxelMaterial>;
+
+/// A chunk material with the default settings, sampling the given block texture array.
+pub fn chunk_material(textures: Handle<Image>) -> ChunkMaterial {
+    ExtendedMaterial {
+        base: StandardMaterial {
+            base_color: Color::WHITE,
+            perceptual_roughness: 0.8,
+            // can be used in forward or deferred mode.
+            opaque_render_method: OpaqueRendererMethod::Auto,
+            // in deferred mode, only the PbrInput can be modified (uvs, color and other material properties),
+            // in forward mode, the output can also be modified after lighting is applied.
+            // see the fragment shader `extended_material.wgsl` for more info.
+            // Note: to run in deferred mode, you must also add a `DeferredPrepass` component to the camera and either
+            // change the above to `OpaqueRendererMethod::Deferred` or add the `DefaultOpaqueRendererMethod` resource.
+            ..default()
+        },
+        extension: VoxelMaterial { quantize_steps: 20, textures },
+    }
+}
+
+/// The material shared by all chunk meshes. The color and texture of each block come from the vertex data, so a single
+/// material can draw every chunk.
+///
+/// Replacing the handle switches every chunk over to the new material.
+#[derive(Resource)]
+pub struct VoxelMaterialHandle(pub Handle<ChunkMaterial>);
+
+impl FromWorld for VoxelMaterialHandle {
+    fn from_world(world: &mut World) -> Self {
+        let textures = world.resource::<VoxelTextures>().array.clone();
+        Self {
+            0: world.resource_mut::<Assets<ChunkMaterial>>().add(chunk_material(textures))
+        }
+    }
+}
+
+/// The material shared by all translucent meshes, like [`VoxelMaterialHandle`] but blended over what is behind it by
+/// the alpha of the block textures. Draws fluids and transparent blocks like glass.
+#[derive(Resource)]
+pub struct TranslucentMaterialHandle(pub Handle<ChunkMaterial>);
+
+impl FromWorld for TranslucentMaterialHandle {
+    fn from_world(world: &mut World) -> Self {
+        let textures = world.resource::<VoxelTextures>().array.clone();
+        let mut material = chunk_material(textures);
+        material.base.alpha_mode = AlphaMode::Blend;
+        Self {
+            0: world.resource_mut::<Assets<ChunkMaterial>>().add(material)
+        }
+    }
+}
+
+/// The texture array holding every block texture, one per layer, in the order given by [`BlockRegistry::textures`].
+#[derive(Resource)]
+pub struct VoxelTextures {
+    pub array: Handle<Image>,
+    sources: Vec<Handle<Image>>,
+    dirty: bool,
+}
+
+impl FromWorld for VoxelTextures {
+    fn from_world(world: &mut World) -> Self {
+        let array = world.resource_mut::<Assets<Image>>().add(create_texture_array(Vec::new(), 1));
+        Self {
+            array,
+            sources: Vec::new(),
+            dirty: false,
+        }
+    }
+}
+
+pub struct VoxelRendererPlugin;
+
+impl Plugin for VoxelRendererPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(MaterialPlugin::<ChunkMaterial>::default())
+            .init_resource::<VoxelTextures>()
+            .init_resource::<VoxelMaterialHandle>()
+            .init_resource::<TranslucentMaterialHandle>()
+            .add_systems(Update, (load_block_textures, build_texture_array).chain());
+    }
+}
+
+fn load_block_textures(registry: Res<BlockRegistry>, asset_server: Res<AssetServer>, mut textures: ResMut<VoxelTextures>) {
+    if !registry.is_changed() {
+        return;
+    }
+    textures.sources = registry.textures().iter().map(|path| asset_server.load(path.clone())).collect();
+    textures.dirty = true;
+}
+
+/// Stacks the block textures into the texture array once they have all loaded, and again whenever one of them changes.
+fn build_texture_array(
+    mut events: EventReader<AssetEvent<Image>>,
+    asset_server: Res<AssetServer>,
+    mut textures: ResMut<VoxelTextures>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<ChunkMaterial>>,
+    material: Res<VoxelMaterialHandle>,
+    translucent_material: Res<TranslucentMaterialHandle>,
+) {
+    for event in events.read() {
+        if let AssetEvent::Modified { id } = event {
+            if textures.sources.iter().any(|source| source.id() == *id) {
+                textures.dirty = true;
+            }
+        }
+    }
+    if !textures.dirty {
+        return;
+    }
+    let pending = textures.sources.iter()
+        .any(|source| !images.contains(source) && asset_server.get_load_state(source) != Some(LoadState::Failed));
+    if pending {
+        return;
+    }
+
+    // Layer 0 stays white so untextured faces only show their base color
+    let mut data = vec![255u8; (TEXTURE_SIZE * TEXTURE_SIZE * 4) as usize];
+    for source in &textures.sources {
+        match images.get(source) {
+            Some(image) if image.width() == TEXTURE_SIZE && image.height() == TEXTURE_SIZE && image.texture_descriptor.format == TextureFormat::Rgba8UnormSrgb => {
+                data.extend_from_slice(&image.data);
+            }
+            _ => {
+                warn!("Block texture {:?} is missing or not a {}x{} RGBA image", source.path(), TEXTURE_SIZE, TEXTURE_SIZE);
+                data.extend(missing_texture());
+            }
+        }
+    }
+
+    let array = textures.array.clone();
+    images.insert(array, create_texture_array(data, textures.sources.len() as u32 + 1));
+    textures.dirty = false;
+
+    // Materials are only prepared again when they change themselves, so touch them to rebind the new texture view
+    for handle in [&material.0, &translucent_material.0] {
+        materials.get_mut(handle);
+    }
+}
+
+fn create_texture_array(mut data: Vec<u8>, layers: u32) -> Image {
+    data.resize((TEXTURE_SIZE * TEXTURE_SIZE * 4 * layers) as usize, 255);
+    let mut image = Image::new(
+        Extent3d {
+            width: TEXTURE_SIZE,
+            height: TEXTURE_SIZE,
+            depth_or_array_layers: layers,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    // Without this a single layer would be bound as a plain 2d texture
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+    // Greedy meshed quads span several blocks and rely on the texture repeating
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        mag_filter: ImageFilterMode::Nearest,
+        min_filter: ImageFilterMode::Nearest,
+        ..default()
+    });
+    return image;
+}
+
+/// A magenta and black checkerboard.
+fn missing_texture() -> impl Iterator<Item=u8> {
+    return (0..TEXTURE_SIZE * TEXTURE_SIZE).flat_map(|i| {
+        let (x, y) = (i % TEXTURE_SIZE, i / TEXTURE_SIZE);
+        if (x / (TEXTURE_SIZE / 2) + y / (TEXTURE_SIZE / 2)) % 2 == 0 { [255, 0, 255, 255] } else { [0, 0, 0, 255] }
+    });
+}
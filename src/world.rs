@@ -1,107 +1,620 @@
-use bevy::math::IVec3;
+use std::collections::{hash_map, HashSet};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 
+use bevy::math::{IVec3, Vec3};
+
+use crate::biome::{Biome, BiomeMap};
+use crate::block::{BlockId, BlockProperties, BlockRegistry};
+use crate::palette::PalettedContainer;
+use crate::region::RegionStorage;
+
+/// The blocks of a single chunk. Uniform chunks, such as all air, only store a single block.
+#[derive(Clone)]
 pub struct RenderChunk {
-    blocks: [i8; VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE],
+    blocks: PalettedContainer<BlockId>,
 }
 
 impl RenderChunk {
-    fn create_solid(block: i8) -> Self {
+    pub const VOLUME: usize = VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE;
+
+    pub fn create_solid(block: BlockId) -> Self {
         Self {
-            blocks: [block; VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE * VoxelWorld::CHUNK_SIZE]
+            blocks: PalettedContainer::filled(RenderChunk::VOLUME, block)
         }
     }
 
-    fn get_block(&self, pos: IVec3) -> i8 {
-        return self.blocks[(pos.x as usize & 15) + ((pos.y as usize & 15) + (pos.z as usize & 15) * VoxelWorld::CHUNK_SIZE) * VoxelWorld::CHUNK_SIZE];
+    fn index(pos: IVec3) -> usize {
+        return (pos.x as usize & 15) + ((pos.y as usize & 15) + (pos.z as usize & 15) * VoxelWorld::CHUNK_SIZE) * VoxelWorld::CHUNK_SIZE;
+    }
+
+    pub fn get_block(&self, pos: IVec3) -> BlockId {
+        return self.blocks.get(RenderChunk::index(pos));
+    }
+
+    pub fn set_block(&mut self, pos: IVec3, block: BlockId) {
+        self.blocks.set(RenderChunk::index(pos), block);
+    }
+
+    pub fn get_index(&self, index: usize) -> BlockId {
+        return self.blocks.get(index);
     }
 
-    fn set_block(&mut self, pos: IVec3, block: i8) {
-        self.blocks[(pos.x as usize & 15) + ((pos.y as usize & 15) + (pos.z as usize & 15) * VoxelWorld::CHUNK_SIZE) * VoxelWorld::CHUNK_SIZE] = block;
+    pub fn set_index(&mut self, index: usize, block: BlockId) {
+        self.blocks.set(index, block);
+    }
+
+    /// The distinct blocks that may be present in this chunk.
+    pub fn palette(&self) -> &[BlockId] {
+        return self.blocks.palette();
+    }
+
+    pub fn compact(&mut self) {
+        self.blocks.compact();
+    }
+
+    /// Whether the block may be present in this chunk. This can return false positives for blocks that were removed.
+    pub fn contains(&self, block: BlockId) -> bool {
+        return self.blocks.contains(block);
     }
 }
 
+/// A sparse, unbounded grid of chunks keyed by chunk position.
+///
+/// Chunks are loaded and unloaded explicitly, so the world can extend in every direction including negative
+/// coordinates. Blocks can only be set in loaded chunks, so an edit never stands in for terrain that is still being
+/// read or generated.
+#[derive(Default)]
 pub struct VoxelWorld {
-    chunks: Vec<RenderChunk>,
-    size: IVec3,
+    chunks: HashMap<IVec3, RenderChunk>,
+    unsaved: HashSet<IVec3>,
+    /// Chunks handed out by [`VoxelWorld::take_unsaved`] whose save has not finished yet.
+    saving: HashSet<IVec3>,
+    dirty: HashSet<IVec3>,
+    revisions: HashMap<IVec3, u64>,
+    /// Fluid blocks to update on the next fluid tick, because they or a neighbour changed.
+    fluid_updates: HashSet<IVec3>,
+    registry: BlockRegistry,
+    biomes: Arc<BiomeMap>,
 }
 
 impl VoxelWorld {
     pub const CHUNK_SIZE: usize = 16;
-    pub const AIR: i8 = 0;
-    pub const STONE: i8 = 1;
 
-    pub fn create(grid_size: i32) -> Self {
-        let mut chunks: Vec<RenderChunk> = Vec::new();
-        for _ in 0..grid_size * grid_size * grid_size {
-            chunks.push(RenderChunk::create_solid(VoxelWorld::AIR));
-        }
+    pub fn create(registry: BlockRegistry) -> Self {
         Self {
-            chunks,
-            size: IVec3::splat(grid_size),
+            chunks: HashMap::new(),
+            unsaved: HashSet::new(),
+            saving: HashSet::new(),
+            dirty: HashSet::new(),
+            revisions: HashMap::new(),
+            fluid_updates: HashSet::new(),
+            registry,
+            biomes: Arc::new(BiomeMap::default()),
         }
     }
 
+    pub fn set_registry(&mut self, registry: BlockRegistry) {
+        self.registry = registry;
+    }
+
+    /// Uses the biomes of the world generator, so [`BlockGetter::biome_at`] matches the generated terrain.
+    pub fn set_biomes(&mut self, biomes: Arc<BiomeMap>) {
+        self.biomes = biomes;
+    }
+
+    /// Converts a block position into the position of the chunk containing it.
+    pub fn chunk_pos(pos: IVec3) -> IVec3 {
+        return pos.div_euclid(IVec3::splat(VoxelWorld::CHUNK_SIZE as i32));
+    }
+
     pub fn get_chunk(&self, chunk_pos: IVec3) -> Option<&RenderChunk> {
-        if chunk_pos.x < 0 || chunk_pos.x >= self.size.x || chunk_pos.y < 0 || chunk_pos.y >= self.size.y || chunk_pos.z < 0 || chunk_pos.z >= self.size.z {
-            return None;
+        return self.chunks.get(&chunk_pos);
+    }
+
+    pub fn is_loaded(&self, chunk_pos: IVec3) -> bool {
+        return self.chunks.contains_key(&chunk_pos);
+    }
+
+    /// Inserts a chunk at the specified position, returning the chunk that was previously there.
+    ///
+    /// The chunk and its neighbours are marked dirty, as the faces along their shared borders may have changed.
+    pub fn load_chunk(&mut self, chunk_pos: IVec3, chunk: RenderChunk) -> Option<RenderChunk> {
+        let previous = self.chunks.insert(chunk_pos, chunk);
+        self.mark_neighbours_dirty(chunk_pos);
+        self.schedule_loaded_fluids(chunk_pos);
+        return previous;
+    }
+
+    /// Schedules every fluid block in a newly loaded chunk, and those along the borders of the six chunks sharing a
+    /// face with it, which could not flow into it while it was missing.
+    fn schedule_loaded_fluids(&mut self, chunk_pos: IVec3) {
+        let with_fluids: HashSet<IVec3> = [IVec3::ZERO, IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z].into_iter()
+            .map(|offset| chunk_pos + offset)
+            .filter(|chunk_pos| self.chunks.get(chunk_pos).is_some_and(|chunk| chunk.palette().iter().any(|block| self.registry.get(*block).fluid.is_some())))
+            .collect();
+        if with_fluids.is_empty() {
+            return;
+        }
+
+        let size = VoxelWorld::CHUNK_SIZE as i32;
+        for z in -1..=size {
+            for y in -1..=size {
+                for x in -1..=size {
+                    let local = IVec3::new(x, y, z);
+                    let pos = chunk_pos * size + local;
+                    // Past more than one side of the chunk is a neighbour only sharing an edge or corner with it
+                    let outside = local.to_array().into_iter().filter(|coordinate| *coordinate < 0 || *coordinate >= size).count();
+                    if outside <= 1 && with_fluids.contains(&VoxelWorld::chunk_pos(pos)) && self.get_properties(pos).fluid.is_some() {
+                        self.fluid_updates.insert(pos);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the chunk at the specified position so it can be saved or discarded.
+    pub fn unload_chunk(&mut self, chunk_pos: IVec3) -> Option<RenderChunk> {
+        self.unsaved.remove(&chunk_pos);
+        self.saving.remove(&chunk_pos);
+        self.dirty.remove(&chunk_pos);
+        self.revisions.remove(&chunk_pos);
+        let chunk = self.chunks.remove(&chunk_pos);
+        self.mark_neighbours_dirty(chunk_pos);
+        return chunk;
+    }
+
+    /// Marks the chunk as needing a new mesh. Marking a chunk more than once before it is rebuilt has no extra cost.
+    pub fn mark_dirty(&mut self, chunk_pos: IVec3) {
+        self.dirty.insert(chunk_pos);
+        *self.revisions.entry(chunk_pos).or_insert(0) += 1;
+    }
+
+    /// Counts how often the chunk was marked dirty, so a mesh built from an older snapshot can be recognised as out of date.
+    pub fn revision(&self, chunk_pos: IVec3) -> u64 {
+        return self.revisions.get(&chunk_pos).copied().unwrap_or(0);
+    }
+
+    /// Marks the chunk and all 26 of its neighbours dirty, skipping those that are empty and have nothing to mesh.
+    fn mark_neighbours_dirty(&mut self, chunk_pos: IVec3) {
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let neighbour = chunk_pos + IVec3::new(x, y, z);
+                    if self.chunks.get(&neighbour).is_some_and(|chunk| chunk.palette() != [BlockId::AIR]) {
+                        self.mark_dirty(neighbour);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns every loaded chunk marked dirty since the last call and clears the marks.
+    pub fn take_dirty(&mut self) -> Vec<IVec3> {
+        let chunks = &self.chunks;
+        return self.dirty.drain()
+            .filter(|chunk_pos| chunks.contains_key(chunk_pos))
+            .collect();
+    }
+
+    /// Returns every loaded position scheduled for a fluid update since the last call and clears the schedule.
+    pub fn take_fluid_updates(&mut self) -> Vec<IVec3> {
+        let chunks = &self.chunks;
+        return self.fluid_updates.drain()
+            .filter(|pos| chunks.contains_key(&VoxelWorld::chunk_pos(*pos)))
+            .collect();
+    }
+
+    /// Whether the chunk was modified since it was last saved, or is still being saved.
+    pub fn is_unsaved(&self, chunk_pos: IVec3) -> bool {
+        return self.unsaved.contains(&chunk_pos) || self.saving.contains(&chunk_pos);
+    }
+
+    /// Returns a copy of every chunk modified since the last save. They count as unsaved until
+    /// [`VoxelWorld::finish_save`] reports that they were written.
+    pub fn take_unsaved(&mut self) -> Vec<(IVec3, RenderChunk)> {
+        let chunks: Vec<(IVec3, RenderChunk)> = self.unsaved.drain()
+            .filter_map(|chunk_pos| self.chunks.get(&chunk_pos).map(|chunk| (chunk_pos, chunk.clone())))
+            .collect();
+        self.saving.extend(chunks.iter().map(|(chunk_pos, _)| *chunk_pos));
+        return chunks;
+    }
+
+    /// Marks chunks taken by [`VoxelWorld::take_unsaved`] as saved, or as unsaved again if writing them failed so the
+    /// next save retries them.
+    pub fn finish_save(&mut self, chunks: &[IVec3], saved: bool) {
+        for chunk_pos in chunks {
+            if self.saving.remove(chunk_pos) && !saved {
+                self.unsaved.insert(*chunk_pos);
+            }
+        }
+    }
+
+    /// Writes every loaded chunk into region files under the directory.
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        let chunks: Vec<(IVec3, RenderChunk)> = self.get_chunks().map(|(chunk_pos, chunk)| (chunk_pos, chunk.clone())).collect();
+        RegionStorage::new(path).write_chunks(&chunks)?;
+        self.unsaved.clear();
+        return Ok(());
+    }
+
+    /// Reads every chunk stored in the region files under the directory.
+    pub fn load(path: &Path, registry: BlockRegistry) -> io::Result<VoxelWorld> {
+        let mut world = VoxelWorld::create(registry);
+        for (chunk_pos, chunk) in RegionStorage::new(path).read_all()? {
+            world.load_chunk(chunk_pos, chunk);
         }
-        return Some(&self.chunks[(chunk_pos.x + (chunk_pos.y + chunk_pos.z * self.size.y) * self.size.x) as usize]);
+        return Ok(world);
     }
 
-    pub fn set_block(&mut self, pos: IVec3, block: i8) {
-        if pos.x < 0 || pos.x >= self.size.x * VoxelWorld::CHUNK_SIZE as i32 || pos.y < 0 || pos.y >= self.size.y * VoxelWorld::CHUNK_SIZE as i32 || pos.z < 0 || pos.z >= self.size.z * VoxelWorld::CHUNK_SIZE as i32 {
+    pub fn set_block(&mut self, pos: IVec3, block: BlockId) {
+        let chunk_pos = VoxelWorld::chunk_pos(pos);
+        let Some(chunk) = self.chunks.get_mut(&chunk_pos) else {
             return;
+        };
+        if chunk.get_block(pos) == block {
+            return;
+        }
+        chunk.set_block(pos, block);
+        self.unsaved.insert(chunk_pos);
+
+        // Fluids react on the next tick to anything changing next to them, including themselves
+        for offset in [IVec3::ZERO, IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z] {
+            if self.get_properties(pos + offset).fluid.is_some() {
+                self.fluid_updates.insert(pos + offset);
+            }
+        }
+
+        // Blocks on the border also show up in the neighbouring meshes, through face culling and ambient occlusion
+        let local = pos - chunk_pos * VoxelWorld::CHUNK_SIZE as i32;
+        let last = VoxelWorld::CHUNK_SIZE as i32 - 1;
+        let offsets = local.to_array().map(|coordinate| match coordinate {
+            0 => -1,
+            c if c == last => 1,
+            _ => 0,
+        });
+        for z in [0, offsets[2]] {
+            for y in [0, offsets[1]] {
+                for x in [0, offsets[0]] {
+                    let neighbour = chunk_pos + IVec3::new(x, y, z);
+                    if self.chunks.contains_key(&neighbour) {
+                        self.mark_dirty(neighbour);
+                    }
+                }
+            }
         }
-        let chunk_pos = pos / VoxelWorld::CHUNK_SIZE as i32;
-        return self.chunks[(chunk_pos.x + (chunk_pos.y + chunk_pos.z * self.size.y) * self.size.x) as usize].set_block(pos, block);
     }
 
-    pub fn get_chunks(&self) -> ChunkIterator {
+    /// Copies the chunk and a one block border from all 26 of its neighbours, so it can be meshed without holding on to the world.
+    pub fn snapshot(&self, chunk_pos: IVec3) -> ChunkSnapshot {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        let mut neighbours: [Option<&RenderChunk>; 27] = [None; 27];
+        for (i, neighbour) in neighbours.iter_mut().enumerate() {
+            let offset = IVec3::new(i as i32 % 3, i as i32 / 3 % 3, i as i32 / 9) - IVec3::ONE;
+            *neighbour = self.get_chunk(chunk_pos + offset);
+        }
+
+        let mut blocks = Vec::with_capacity(ChunkSnapshot::VOLUME);
+        for z in -1..=SIZE {
+            for y in -1..=SIZE {
+                for x in -1..=SIZE {
+                    let pos = IVec3::new(x, y, z);
+                    let offset = pos.div_euclid(IVec3::splat(SIZE)) + IVec3::ONE;
+                    let block = match neighbours[(offset.x + offset.y * 3 + offset.z * 9) as usize] {
+                        Some(chunk) => chunk.get_block(pos),
+                        None => BlockId::AIR,
+                    };
+                    blocks.push(block);
+                }
+            }
+        }
+
+        ChunkSnapshot {
+            origin: chunk_pos * SIZE,
+            blocks,
+            registry: self.registry.clone(),
+            biome_map: self.biomes.clone(),
+            biomes: OnceLock::new(),
+        }
+    }
+
+    pub fn get_chunks(&self) -> ChunkIterator<'_> {
         ChunkIterator {
-            pointer: 0,
-            world: self,
+            inner: self.chunks.iter(),
         }
     }
 }
 
 impl BlockGetter for VoxelWorld {
-    fn get_block(&self, pos: IVec3) -> i8 {
-        if pos.x < 0 || pos.x >= self.size.x * VoxelWorld::CHUNK_SIZE as i32 || pos.y < 0 || pos.y >= self.size.y * VoxelWorld::CHUNK_SIZE as i32 || pos.z < 0 || pos.z >= self.size.z * VoxelWorld::CHUNK_SIZE as i32 {
-            return VoxelWorld::AIR;
+    fn registry(&self) -> &BlockRegistry {
+        return &self.registry;
+    }
+
+    fn get_block(&self, pos: IVec3) -> BlockId {
+        return match self.get_chunk(VoxelWorld::chunk_pos(pos)) {
+            Some(chunk) => chunk.get_block(pos),
+            None => BlockId::AIR,
+        };
+    }
+
+    fn biome_at(&self, pos: IVec3) -> Biome {
+        return self.biomes.biome_at(pos);
+    }
+}
+
+/// An owned copy of a chunk plus a one block border around it. Blocks outside of that are air.
+pub struct ChunkSnapshot {
+    origin: IVec3,
+    blocks: Vec<BlockId>,
+    registry: BlockRegistry,
+    biome_map: Arc<BiomeMap>,
+    /// The biome of each column of the chunk, looked up on first use so it happens on the meshing task.
+    biomes: OnceLock<Vec<Biome>>,
+}
+
+impl ChunkSnapshot {
+    const SIZE: usize = VoxelWorld::CHUNK_SIZE + 2;
+    const VOLUME: usize = ChunkSnapshot::SIZE * ChunkSnapshot::SIZE * ChunkSnapshot::SIZE;
+
+    /// The position of the first block of the chunk, excluding the border.
+    pub fn origin(&self) -> IVec3 {
+        return self.origin;
+    }
+}
+
+impl BlockGetter for ChunkSnapshot {
+    fn registry(&self) -> &BlockRegistry {
+        return &self.registry;
+    }
+
+    fn get_block(&self, pos: IVec3) -> BlockId {
+        let local = pos - self.origin + IVec3::ONE;
+        if local.min_element() < 0 || local.max_element() >= ChunkSnapshot::SIZE as i32 {
+            return BlockId::AIR;
+        }
+        return self.blocks[local.x as usize + (local.y as usize + local.z as usize * ChunkSnapshot::SIZE) * ChunkSnapshot::SIZE];
+    }
+
+    fn biome_at(&self, pos: IVec3) -> Biome {
+        const SIZE: i32 = VoxelWorld::CHUNK_SIZE as i32;
+
+        let local = pos - self.origin;
+        if local.x < 0 || local.z < 0 || local.x >= SIZE || local.z >= SIZE {
+            return self.biome_map.biome_at(pos);
         }
-        let chunk_pos = pos / VoxelWorld::CHUNK_SIZE as i32;
-        return return self.get_chunk(chunk_pos).unwrap().get_block(pos);
+        let biomes = self.biomes.get_or_init(|| {
+            (0..SIZE * SIZE).map(|i| self.biome_map.biome_at(self.origin + IVec3::new(i % SIZE, 0, i / SIZE))).collect()
+        });
+        return biomes[(local.x + local.z * SIZE) as usize];
     }
 }
 
 pub struct ChunkIterator<'a> {
-    pointer: i32,
-    world: &'a VoxelWorld,
+    inner: hash_map::Iter<'a, IVec3, RenderChunk>,
 }
 
 impl<'a> Iterator for ChunkIterator<'a> {
     type Item = (IVec3, &'a RenderChunk);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let chunk_pos = IVec3::new(self.pointer % self.world.size.x, self.pointer / (self.world.size.x * self.world.size.y), self.pointer / self.world.size.x);
-        return self.world.get_chunk(chunk_pos).and_then(|chunk| {
-            Some((chunk_pos, chunk))
-        });
+        return self.inner.next().map(|(chunk_pos, chunk)| (*chunk_pos, chunk));
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Face {
+    Down,
+    Up,
+    North,
+    South,
+    West,
+    East,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [Face::Down, Face::Up, Face::North, Face::South, Face::West, Face::East];
+
+    pub fn normal(self) -> IVec3 {
+        return match self {
+            Face::Down => IVec3::NEG_Y,
+            Face::Up => IVec3::Y,
+            Face::North => IVec3::NEG_Z,
+            Face::South => IVec3::Z,
+            Face::West => IVec3::NEG_X,
+            Face::East => IVec3::X,
+        };
+    }
+
+    /// The two axes spanning the face, ordered so that `u × v` points along the normal and quads
+    /// wound `0, u, u + v, v` are front facing.
+    pub fn axes(self) -> (IVec3, IVec3) {
+        return match self {
+            Face::Down => (IVec3::X, IVec3::Z),
+            Face::Up => (IVec3::Z, IVec3::X),
+            Face::North => (IVec3::Y, IVec3::X),
+            Face::South => (IVec3::X, IVec3::Y),
+            Face::West => (IVec3::Z, IVec3::Y),
+            Face::East => (IVec3::Y, IVec3::Z),
+        };
     }
 }
 
+/// Where a ray first entered a block.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RaycastHit {
+    pub pos: IVec3,
+    /// Normal of the face the ray entered through, zero if the ray started inside the block.
+    pub normal: IVec3,
+    /// Distance from the ray origin to where it entered the block.
+    pub distance: f32,
+}
+
 pub trait BlockGetter {
-    fn get_block(&self, pos: IVec3) -> i8;
+    fn registry(&self) -> &BlockRegistry;
+
+    fn get_block(&self, pos: IVec3) -> BlockId;
+
+    fn biome_at(&self, pos: IVec3) -> Biome;
+
+    fn get_properties(&self, pos: IVec3) -> &BlockProperties {
+        return self.registry().get(self.get_block(pos));
+    }
 
     fn should_render_block(&self, pos: IVec3) -> bool {
-        return self.get_block(pos) == VoxelWorld::STONE;
+        return self.get_properties(pos).is_visible();
     }
 
     fn should_render_face(&self, pos: IVec3, offset: IVec3) -> bool {
-        if self.get_block(pos) == VoxelWorld::AIR {
+        let block = self.get_block(pos);
+        if !self.registry().get(block).is_visible() {
+            return false;
+        }
+
+        let neighbour = self.get_block(pos + offset);
+        let properties = self.registry().get(neighbour);
+        if properties.opaque {
             return false;
         }
+        // Faces between two of the same transparent block, like a wall of glass, are never seen
+        return !(properties.transparent && neighbour == block);
+    }
+
+    fn is_collidable(&self, pos: IVec3) -> bool {
+        return self.get_properties(pos).collidable;
+    }
+
+    /// The first solid block along the ray, if one is closer than `max_distance`.
+    fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RaycastHit> {
+        return self.raycast_where(origin, direction, max_distance, &|properties| properties.solid);
+    }
+
+    /// The first block along the ray for which `hits` is true, if one is closer than `max_distance`.
+    fn raycast_where(&self, origin: Vec3, direction: Vec3, max_distance: f32, hits: &dyn Fn(&BlockProperties) -> bool) -> Option<RaycastHit> {
+        let direction = direction.normalize_or_zero();
+        if direction == Vec3::ZERO {
+            return None;
+        }
+
+        // Walk the grid one block at a time, always stepping along the axis whose next block boundary is closest
+        let mut pos = origin.floor().as_ivec3();
+        let mut step = IVec3::ZERO;
+        let mut next_boundary = Vec3::INFINITY;
+        let mut boundary_spacing = Vec3::INFINITY;
+        for axis in 0..3 {
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                next_boundary[axis] = (pos[axis] as f32 + 1.0 - origin[axis]) / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                next_boundary[axis] = (origin[axis] - pos[axis] as f32) / -direction[axis];
+            }
+            boundary_spacing[axis] = 1.0 / direction[axis].abs();
+        }
+
+        let mut normal = IVec3::ZERO;
+        let mut distance = 0.0;
+        loop {
+            if hits(self.get_properties(pos)) {
+                return Some(RaycastHit { pos, normal, distance });
+            }
+
+            let axis = if next_boundary.x < next_boundary.y && next_boundary.x < next_boundary.z {
+                0
+            } else if next_boundary.y < next_boundary.z {
+                1
+            } else {
+                2
+            };
+            distance = next_boundary[axis];
+            if distance > max_distance {
+                return None;
+            }
+            pos[axis] += step[axis];
+            next_boundary[axis] += boundary_spacing[axis];
+            normal = IVec3::ZERO;
+            normal[axis] = -step[axis];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STONE: BlockId = BlockId(1);
+
+    /// A world of empty chunks from -2 to 1 on every axis with stone at the given positions.
+    fn world_with(stone: &[IVec3]) -> VoxelWorld {
+        let mut world = VoxelWorld::create(BlockRegistry::builtin());
+        for z in -2..2 {
+            for y in -2..2 {
+                for x in -2..2 {
+                    world.load_chunk(IVec3::new(x, y, z), RenderChunk::create_solid(BlockId::AIR));
+                }
+            }
+        }
+        for pos in stone {
+            world.set_block(*pos, STONE);
+        }
+        return world;
+    }
+
+    fn assert_hit(hit: Option<RaycastHit>, pos: IVec3, normal: IVec3, distance: f32) {
+        let hit = hit.expect("the ray should hit a block");
+        assert_eq!((hit.pos, hit.normal), (pos, normal));
+        assert!((hit.distance - distance).abs() < 1e-5, "distance {} instead of {}", hit.distance, distance);
+    }
+
+    #[test]
+    fn axis_aligned_rays_hit_the_face_towards_them() {
+        let world = world_with(&[IVec3::new(3, 0, 0), IVec3::new(0, -2, 0), IVec3::new(0, 0, -4)]);
+        let origin = Vec3::splat(0.5);
+        assert_hit(world.raycast(origin, Vec3::X, 10.0), IVec3::new(3, 0, 0), IVec3::NEG_X, 2.5);
+        assert_hit(world.raycast(origin, Vec3::NEG_Y, 10.0), IVec3::new(0, -2, 0), IVec3::Y, 1.5);
+        assert_hit(world.raycast(origin, Vec3::NEG_Z, 10.0), IVec3::new(0, 0, -4), IVec3::Z, 3.5);
+        assert!(world.raycast(origin, Vec3::Y, 10.0).is_none());
+    }
+
+    #[test]
+    fn rays_crossing_chunk_borders_keep_their_distance() {
+        let world = world_with(&[IVec3::new(-20, 0, 0)]);
+        assert_hit(world.raycast(Vec3::new(2.25, 0.5, 0.5), Vec3::NEG_X, 30.0), IVec3::new(-20, 0, 0), IVec3::X, 21.25);
+    }
+
+    #[test]
+    fn diagonal_rays_report_the_face_they_entered_through() {
+        // The ray crosses x = 1 before y = 1, so it enters the block above the first one it steps into from below
+        let world = world_with(&[IVec3::new(1, 1, 0)]);
+        let direction = Vec3::new(1.0, 0.5, 0.0);
+        assert_hit(world.raycast(Vec3::splat(0.5), direction, 10.0), IVec3::new(1, 1, 0), IVec3::NEG_Y, direction.length());
 
-        return self.get_block(pos + offset) == VoxelWorld::AIR;
+        let world = world_with(&[IVec3::new(1, 0, 0)]);
+        assert_hit(world.raycast(Vec3::splat(0.5), direction, 10.0), IVec3::new(1, 0, 0), IVec3::NEG_X, 0.5 * direction.length());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rays_starting_inside_a_block_hit_it_without_a_normal() {
+        let world = world_with(&[IVec3::ZERO]);
+        assert_hit(world.raycast(Vec3::splat(0.5), Vec3::X, 10.0), IVec3::ZERO, IVec3::ZERO, 0.0);
+    }
+
+    #[test]
+    fn rays_stop_at_their_max_distance() {
+        let world = world_with(&[IVec3::new(3, 0, 0)]);
+        let origin = Vec3::splat(0.5);
+        assert!(world.raycast(origin, Vec3::X, 2.49).is_none());
+        assert_hit(world.raycast(origin, Vec3::X, 2.5), IVec3::new(3, 0, 0), IVec3::NEG_X, 2.5);
+        assert!(world.raycast(origin, Vec3::ZERO, 10.0).is_none());
+    }
+
+    #[test]
+    fn raycast_where_only_hits_matching_blocks() {
+        let world = world_with(&[IVec3::new(3, 0, 0)]);
+        let origin = Vec3::splat(0.5);
+        assert!(world.raycast_where(origin, Vec3::X, 10.0, &|properties| properties.name == "glass").is_none());
+        assert_hit(world.raycast_where(origin, Vec3::X, 10.0, &|properties| !properties.solid), IVec3::ZERO, IVec3::ZERO, 0.0);
+    }
+}